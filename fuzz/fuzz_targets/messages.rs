@@ -0,0 +1,33 @@
+#![no_main]
+#[macro_use]
+extern crate serde;
+
+#[allow(dead_code)]
+#[path = "../../src/error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../../src/message.rs"]
+mod message;
+#[allow(dead_code)]
+#[path = "../../src/structs.rs"]
+mod structs;
+
+use libfuzzer_sys::fuzz_target;
+use message::{ClientMessage, DaemonMessage, Request, Response};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(req) = serde_json::from_slice::<Request>(data) {
+        let _ = serde_json::to_vec(&req).map(|v| serde_json::from_slice::<Request>(&v));
+    }
+    if let Ok(res) = serde_json::from_slice::<Response>(data) {
+        let _ = serde_json::to_vec(&res).map(|v| serde_json::from_slice::<Response>(&v));
+    }
+    if let Ok(s) = std::str::from_utf8(data) {
+        if let Ok(msg) = s.parse::<ClientMessage>() {
+            let _ = msg.to_string().parse::<ClientMessage>();
+        }
+        if let Ok(msg) = s.parse::<DaemonMessage>() {
+            let _ = msg.to_string().parse::<DaemonMessage>();
+        }
+    }
+});
@@ -1,7 +1,13 @@
 use std::env;
 use std::str::FromStr;
+use std::sync::Once;
 
-pub fn init(appname: Option<&str>) {
+static INIT: Once = Once::new();
+
+/// Sets the logger up once per process, however many threads of a host process call in
+pub fn init(appname: Option<&str>) { INIT.call_once(|| init_syslog(appname)) }
+
+fn init_syslog(appname: Option<&str>) {
     let log_level_text = include_str!(concat!(env!("OUT_DIR"), "/log-level.txt"));
     let log_level_env = env::var("LOG_LEVEL").unwrap_or(log_level_text.to_string());
     let log_level = log::LevelFilter::from_str(&log_level_env).unwrap_or(log::LevelFilter::Off);
@@ -48,7 +48,7 @@ impl Buffer {
     }
 
     pub fn write_string(&mut self, s: &str) -> Result<*mut libc::c_char, Error> {
-        let cs = CString::new(s).unwrap();
+        let cs = CString::new(s).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
         self.write(cs.as_ptr(), s.len() + 1)
     }
 
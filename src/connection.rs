@@ -1,51 +1,164 @@
 use crate::applog;
 use crate::error;
+use crate::error::ParseMessageError;
 use crate::message::*;
+use crate::statics::CLIENT_CONF_PATH;
 use crate::structs::SocketConfig as Config;
-use std::os::unix::net::UnixDatagram;
+use std::cell::Cell;
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Duration;
 
+/// Replies longer than this are taken as garbage rather than allocated, as the length comes from the peer
+const MAX_RESPONSE_LEN: usize = 16 * 1024 * 1024;
+
+/// The protocol version agreed with the daemon, once per process; 0 until then
+static NEGOTIATED: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    /// Where to find the daemon, read once per process
+    pub static ref SOCKET_CONF: Config = Config::from_path(&CLIENT_CONF_PATH);
+}
+
 #[derive(Debug)]
 pub struct Connection {
-    conf: Config,
-    conn: UnixDatagram,
+    conn: UnixStream,
+    next_id: Cell<u64>,
 }
 
 impl Connection {
     pub fn new(logid: &str) -> Result<Self, error::Error> {
         applog::init(Some("sectora"));
         log::debug!("{}", logid);
-        let conf = Config::new();
-        let conn = Self::connect_daemon(&conf)?;
-        Ok(Self { conf, conn })
+        let conn = Self { conn: Self::connect_daemon(&SOCKET_CONF)?,
+                          next_id: Cell::new(1) };
+        if NEGOTIATED.load(Ordering::SeqCst) == 0 {
+            NEGOTIATED.store(conn.negotiate()?, Ordering::SeqCst);
+        }
+        Ok(conn)
+    }
+
+    /// Agrees on the newest protocol version both ends speak, asking in the oldest one so that any daemon reads it
+    fn negotiate(&self) -> Result<u32, error::Error> {
+        match self.exchange(MIN_PROTOCOL_VERSION, ClientMessage::Hello { version: PROTOCOL_VERSION })? {
+            DaemonMessage::Hello { version } if version >= MIN_PROTOCOL_VERSION && version <= PROTOCOL_VERSION => {
+                log::debug!("negotiated protocol version {}", version);
+                Ok(version)
+            }
+            msg => {
+                log::debug!("ERROR: handshake failed, {}", msg);
+                Err(error::Error::from(ParseMessageError::UnsupportedVersion))
+            }
+        }
+    }
+
+    /// Talks over a stream that is already connected, such as one end of a pair standing in for the daemon
+    #[cfg(test)]
+    pub fn from_stream(conn: UnixStream) -> Self {
+        Self { conn,
+               next_id: Cell::new(1) }
     }
 
-    fn socket_path(conf: &Config) -> String { format!("{}/{}", &conf.socket_dir, std::process::id()) }
+    /// Refuses a daemon socket that anyone but root could have put in place
+    fn verify_daemon_socket(path: &str) -> Result<(), Error> {
+        let untrusted =
+            |what: &str| Err(Error::new(ErrorKind::PermissionDenied, format!("untrusted {}: {}", what, path)));
+        let socket = fs::symlink_metadata(path)?;
+        if socket.uid() != 0 || !socket.file_type().is_socket() {
+            return untrusted("daemon socket");
+        }
+        let dir = fs::symlink_metadata(Path::new(path).parent().unwrap_or_else(|| Path::new("/")))?;
+        if dir.uid() != 0 || !dir.is_dir() || dir.mode() & 0o022 != 0 {
+            return untrusted("directory of daemon socket");
+        }
+        Ok(())
+    }
 
-    fn connect_daemon(conf: &Config) -> Result<UnixDatagram, error::Error> {
-        let socket = UnixDatagram::bind(&Self::socket_path(conf))?;
+    fn connect_daemon(conf: &Config) -> Result<UnixStream, error::Error> {
+        Self::verify_daemon_socket(&conf.socket_path)?;
+        let socket = UnixStream::connect(&conf.socket_path)?;
         log::debug!("{:?}", socket);
-        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
-        socket.connect(&conf.socket_path)?;
+        let timeout = Duration::from_secs(std::cmp::max(conf.timeout, 1));
+        socket.set_read_timeout(Some(timeout))?;
+        socket.set_write_timeout(Some(timeout))?;
         Ok(socket)
     }
 
+    /// Sends a request in the version agreed with the daemon. A daemon replaced by one that no longer speaks it
+    /// has the version agreed again on the next connection.
     pub fn communicate(&self, msg: ClientMessage) -> Result<DaemonMessage, error::Error> {
-        self.conn.send(msg.to_string().as_bytes())?;
-        let mut buf = [0u8; 4096];
-        let recv_cnt = match self.conn.recv(&mut buf) {
-            Ok(cnt) => cnt,
-            Err(e) => {
-                log::debug!("ERROR: failed to recv msg, {}", e);
-                return Err(error::Error::from(e));
-            }
+        let version = match NEGOTIATED.load(Ordering::SeqCst) {
+            0 => PROTOCOL_VERSION,
+            version => version,
         };
-        let s = String::from_utf8(buf[..recv_cnt].to_vec()).unwrap();
-        log::debug!("recieved: {}", s);
-        Ok(s.parse::<DaemonMessage>()?)
+        let res = self.exchange(version, msg);
+        let unsupported = match &res {
+            Ok(DaemonMessage::Error { code, .. }) => *code == ErrorCode::UnsupportedVersion,
+            Err(error::Error::ParseMessageError(ParseMessageError::UnsupportedVersion)) => true,
+            _ => false,
+        };
+        if unsupported {
+            NEGOTIATED.store(0, Ordering::SeqCst);
+        }
+        res
     }
-}
 
-impl Drop for Connection {
-    fn drop(&mut self) { std::fs::remove_file(&Self::socket_path(&self.conf)).unwrap_or_default(); }
+    /// Sends a request in a frame (a big-endian u32 length and the JSON) and reads the response framed the same way
+    fn exchange(&self, version: u32, msg: ClientMessage) -> Result<DaemonMessage, error::Error> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let req = serde_json::to_vec(&Request { version,
+                                                id,
+                                                message: msg })?;
+        let mut frame = (req.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&req);
+        self.send_all(&frame)?;
+        let mut conn = &self.conn;
+        let mut len = [0u8; 4];
+        if let Err(e) = conn.read_exact(&mut len) {
+            log::debug!("ERROR: failed to recv msg, {}", e);
+            return Err(error::Error::from(e));
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_RESPONSE_LEN {
+            return Err(error::Error::from(Error::new(ErrorKind::InvalidData, format!("response too large: {}", len))));
+        }
+        let mut buf = vec![0u8; len];
+        conn.read_exact(&mut buf)?;
+        let res = serde_json::from_slice::<Response>(&buf)?;
+        log::debug!("recieved #{}: {}", res.id, res.message);
+        if res.version < MIN_PROTOCOL_VERSION || res.version > PROTOCOL_VERSION {
+            return Err(error::Error::from(ParseMessageError::UnsupportedVersion));
+        }
+        if res.id != id {
+            return Err(error::Error::from(ParseMessageError::ParseDaemonMessageError));
+        }
+        Ok(res.message)
+    }
+
+    /// Writes with MSG_NOSIGNAL, as a daemon hanging up must not raise SIGPIPE in the host process
+    fn send_all(&self, mut buf: &[u8]) -> Result<(), error::Error> {
+        while !buf.is_empty() {
+            let sent = unsafe {
+                libc::send(self.conn.as_raw_fd(),
+                           buf.as_ptr() as *const libc::c_void,
+                           buf.len(),
+                           libc::MSG_NOSIGNAL)
+            };
+            if sent < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error::Error::from(err));
+            }
+            buf = &buf[sent as usize..];
+        }
+        Ok(())
+    }
 }
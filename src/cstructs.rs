@@ -1,4 +1,5 @@
 use crate::buffer::Buffer;
+use crate::structs::Aging;
 use std::io::Error;
 
 #[repr(C)]
@@ -27,9 +28,10 @@ impl Passwd {
         Ok(())
     }
 
-    pub fn pack_args(&mut self, buf: &mut Buffer, name: &str, id: u64, gid: u64, home: &str, sh: &str)
+    #[allow(clippy::too_many_arguments)]
+    pub fn pack_args(&mut self, buf: &mut Buffer, name: &str, id: u64, gid: u64, home: &str, sh: &str, gecos: &str)
                      -> Result<(), Error> {
-        self.pack(buf, name, "x", id as libc::uid_t, gid as libc::gid_t, "", home, sh)
+        self.pack(buf, name, "x", id as libc::uid_t, gid as libc::gid_t, gecos, home, sh)
     }
 }
 
@@ -63,8 +65,17 @@ impl Spwd {
         Ok(())
     }
 
-    pub fn pack_args(&mut self, buf: &mut Buffer, name: &str, pass: &str) -> Result<(), Error> {
-        self.pack(buf, name, pass, -1, -1, -1, -1, -1, -1, 0)
+    pub fn pack_args(&mut self, buf: &mut Buffer, name: &str, pass: &str, aging: &Aging) -> Result<(), Error> {
+        self.pack(buf,
+                  name,
+                  pass,
+                  aging.lstchg as libc::c_long,
+                  aging.min as libc::c_long,
+                  aging.max as libc::c_long,
+                  aging.warn as libc::c_long,
+                  aging.inact as libc::c_long,
+                  aging.expire as libc::c_long,
+                  0)
     }
 }
 
@@ -90,3 +101,67 @@ impl Group {
         self.pack(buf, name, "x", id as libc::gid_t, &members)
     }
 }
+
+#[repr(C)]
+pub struct Sgrp {
+    namp: *mut libc::c_char,
+    passwd: *mut libc::c_char,
+    adm: *mut *mut libc::c_char,
+    mem: *mut *mut libc::c_char,
+}
+
+impl Sgrp {
+    fn pack(&mut self, buf: &mut Buffer, namp: &str, passwd: &str, adm: &[&str], mem: &[&str]) -> Result<(), Error> {
+        self.namp = buf.write_string(namp)?;
+        self.passwd = buf.write_string(passwd)?;
+        self.adm = buf.write_vecstr(adm)?;
+        self.mem = buf.write_vecstr(mem)?;
+        Ok(())
+    }
+
+    pub fn pack_args(&mut self, buf: &mut Buffer, name: &str, admins: &[&str], members: &[&str]) -> Result<(), Error> {
+        self.pack(buf, name, "!", admins, members)
+    }
+}
+
+/// `struct __netgrent` of glibc, which the caller allocates and keeps across one enumeration
+#[allow(dead_code)]
+#[repr(C)]
+pub struct Netgrent {
+    kind: libc::c_int,
+    host: *const libc::c_char,
+    user: *const libc::c_char,
+    domain: *const libc::c_char,
+    data: *mut libc::c_char,
+    data_size: libc::size_t,
+    position: libc::c_ulong,
+    first: libc::c_int,
+    known_groups: *mut libc::c_void,
+    needed_groups: *mut libc::c_void,
+    nip: *mut libc::c_void,
+}
+
+impl Netgrent {
+    const TRIPLE_VAL: libc::c_int = 0;
+
+    /// The session token of the enumeration is kept in `position`, which only the module reads
+    pub fn session(&self) -> u64 { self.position as u64 }
+
+    pub fn set_session(&mut self, token: u64) { self.position = token as libc::c_ulong }
+
+    fn write_field(buf: &mut Buffer, field: Option<&str>) -> Result<*const libc::c_char, Error> {
+        match field {
+            Some(s) => Ok(buf.write_string(s)? as *const libc::c_char),
+            None => Ok(std::ptr::null()),
+        }
+    }
+
+    pub fn pack_args(&mut self, buf: &mut Buffer, host: Option<&str>, user: Option<&str>, domain: Option<&str>)
+                     -> Result<(), Error> {
+        self.kind = Self::TRIPLE_VAL;
+        self.host = Self::write_field(buf, host)?;
+        self.user = Self::write_field(buf, user)?;
+        self.domain = Self::write_field(buf, domain)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use crate::message::DaemonMessage;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The database a cursor enumerates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Database {
+    Passwd,
+    Shadow,
+    Group,
+    Gshadow,
+    Netgroup,
+}
+
+/// Who opened a cursor: the database and the uid of the caller, `None` on the legacy socket,
+/// where datagrams do not tell who sent them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Opener {
+    pub database: Database,
+    pub uid: Option<u32>,
+}
+
+/// A cursor was asked for by someone other than its opener, or for another database
+#[derive(Debug)]
+pub struct Refused;
+
+#[derive(Debug)]
+struct Cursor {
+    ents: VecDeque<DaemonMessage>,
+    opener: Opener,
+    touched: Instant,
+}
+
+/// Entries left to enumerate per session token; cursors untouched for `ttl` are taken as abandoned
+#[derive(Debug)]
+pub struct Cursors {
+    cursors: HashMap<u64, Cursor>,
+    ttl: Duration,
+}
+
+impl Cursors {
+    pub fn new(ttl: Duration) -> Self {
+        Self { cursors: HashMap::new(),
+               ttl }
+    }
+
+    /// Refused if another opener's cursor is live under the same token
+    pub fn open(&mut self, token: u64, opener: Opener, ents: VecDeque<DaemonMessage>) -> Result<(), Refused> {
+        self.evict();
+        if let Some(cursor) = self.cursors.get(&token) {
+            if cursor.opener != opener {
+                return Err(Refused);
+            }
+        }
+        self.cursors.insert(token,
+                            Cursor { ents,
+                                     opener,
+                                     touched: Instant::now() });
+        Ok(())
+    }
+
+    pub fn next(&mut self, token: u64, opener: Opener) -> Result<Option<DaemonMessage>, Refused> {
+        let ttl = self.ttl;
+        match self.cursors.get_mut(&token) {
+            Some(cursor) if cursor.opener != opener => Err(Refused),
+            Some(cursor) if cursor.touched.elapsed() < ttl => {
+                cursor.touched = Instant::now();
+                Ok(cursor.ents.pop_front())
+            }
+            Some(_) => {
+                self.cursors.remove(&token);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn close(&mut self, token: u64, opener: Opener) -> Result<(), Refused> {
+        match self.cursors.get(&token) {
+            Some(cursor) if cursor.opener != opener => Err(Refused),
+            _ => {
+                self.cursors.remove(&token);
+                Ok(())
+            }
+        }
+    }
+
+    fn evict(&mut self) {
+        let ttl = self.ttl;
+        self.cursors.retain(|token, cursor| {
+                        let alive = cursor.touched.elapsed() < ttl;
+                        if !alive {
+                            log::debug!("evicted abandoned cursor {}", token);
+                        }
+                        alive
+                    });
+    }
+}
@@ -6,6 +6,8 @@ extern crate hyper_tls;
 extern crate lazy_static;
 extern crate libc;
 extern crate log;
+extern crate nix;
+extern crate openssl;
 #[macro_use]
 extern crate serde;
 extern crate sd_notify;
@@ -16,129 +18,545 @@ extern crate tokio;
 extern crate toml;
 
 mod applog;
+mod cursor;
 mod error;
 mod ghclient;
 mod message;
+mod negcache;
+mod pwchange;
+mod snapshot;
 mod statics;
 mod structs;
+mod webhook;
 
+use cursor::{Cursors, Database, Opener, Refused};
 use error::Error;
-use ghclient::GithubClient;
+use ghclient::{GithubClient, SharedClient};
 use message::*;
-use statics::CONF_PATH;
-use std::collections::hash_map::Entry;
+use negcache::{Miss, NegativeCache};
+use pwchange::PasswordChanges;
+use snapshot::{PwEntry, Snapshot};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockopt, sockopt, UnixCredentials};
+use statics::{CLIENT_CONF_PATH, CONF_PATH};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, VecDeque};
+use std::env;
 use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::os::unix;
-use std::path::Path;
-use structs::{Config, SocketConfig, UserConfig};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+use structs::{Aging, Config, GecosPolicy, OfflinePolicy, Profile, SectorGroup, SocketConfig, UserConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const SD_LISTEN_FDS_START: RawFd = 3;
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+const CURSOR_TTL: Duration = Duration::from_secs(300);
 
 #[tokio::main]
 async fn main() {
     applog::init(Some("sectorad"));
-    let mut d = Daemon::new();
+    let d = Arc::new(Daemon::new());
     d.run().await.expect("run");
     log::debug!("Run stopped");
 }
 
 struct Daemon {
-    client: GithubClient,
+    client: SharedClient,
     socket_conf: SocketConfig,
-    msg_cache: HashMap<u32, VecDeque<DaemonMessage>>,
-}
-
-impl Drop for Daemon {
-    fn drop(&mut self) {
-        log::debug!("Drop daemon");
-        fs::remove_file(&self.socket_conf.socket_path).expect("remove socket");
-    }
+    cursors: Mutex<Cursors>,
+    negcache: Mutex<NegativeCache>,
+    snapshot: AtomicU64,
+    degraded: Arc<AtomicBool>,
+    keys: Arc<RwLock<HashMap<String, String>>>,
+    profiles: Arc<RwLock<HashMap<String, Profile>>>,
 }
 
 impl Daemon {
     fn new() -> Self {
         let config = Config::from_path(&(*CONF_PATH)).expect("valid config");
-        let socket_conf = SocketConfig::new();
-        fs::create_dir_all(&socket_conf.socket_dir).expect("create socket dir");
-        fs::set_permissions(&socket_conf.socket_dir, unix::fs::PermissionsExt::from_mode(0o777)).unwrap_or_default();
-        let client = GithubClient::new(&config);
+        config.validate().expect("valid config");
+        let socket_conf = SocketConfig::load(&CLIENT_CONF_PATH).expect("valid client config");
+        prepare_socket_dir(&socket_conf.socket_path).expect("dedicated socket dir");
+        let degraded = Arc::new(AtomicBool::new(false));
+        let client = Arc::new(RwLock::new(Arc::new(GithubClient::new(&config, degraded.clone()))));
         log::debug!("Initialised");
         Daemon { client,
                  socket_conf,
-                 msg_cache: HashMap::new() }
+                 cursors: Mutex::new(Cursors::new(CURSOR_TTL)),
+                 negcache: Mutex::new(NegativeCache::new(config.negative_cache_ttl, config.negative_cache_size)),
+                 snapshot: AtomicU64::new(0),
+                 degraded,
+                 keys: Arc::new(RwLock::new(HashMap::new())),
+                 profiles: Arc::new(RwLock::new(HashMap::new())) }
+    }
+
+    async fn run(self: Arc<Self>) -> Result<(), Error> {
+        let client = self.client();
+        tokio::spawn(prefetch(self.client.clone(),
+                              self.keys.clone(),
+                              "keys",
+                              |_| true,
+                              |client, login, policy| async move { client.get_user_public_key(&login, policy).await }));
+        // NOTE: profiles are only used for the gecos field
+        tokio::spawn(prefetch(self.client.clone(),
+                              self.profiles.clone(),
+                              "profile",
+                              |conf| conf.gecos != GecosPolicy::Off,
+                              |client, login, _| async move { client.get_user_profile(&login).await }));
+        if let Some(webhook_conf) = client.conf.webhook.clone() {
+            let (refresh, refreshes) = mpsc::channel::<()>(1);
+            tokio::spawn(self.clone().refresh_on_webhook(refreshes));
+            let serving = webhook::serve(self.client.clone(), webhook_conf, refresh);
+            tokio::spawn(async move {
+                if let Err(e) = serving.await {
+                    log::warn!("webhook receiver stopped: {:?}", e);
+                }
+            });
+        }
+        tokio::spawn(watchdog());
+        tokio::spawn(self.clone().publish_snapshots());
+        let socket_path = &self.socket_conf.socket_path;
+        let (listener, activated) = match activated_listener().filter(|l| listens_at(l, socket_path)) {
+            Some(listener) => (listener, true),
+            None => {
+                remove_stale_socket(socket_path)?;
+                let listener = unix::net::UnixListener::bind(socket_path)?;
+                fs::set_permissions(socket_path, unix::fs::PermissionsExt::from_mode(0o666)).unwrap_or_default();
+                (listener, false)
+            }
+        };
+        listener.set_nonblocking(true)?;
+        let mut listener = UnixListener::from_std(listener)?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+        log::info!("Start running @ {}{}",
+                   &self.socket_conf.socket_path,
+                   if activated { " (socket activated)" } else { "" });
+        tokio::spawn(self.clone().warm_up());
+        let legacy = client.conf.legacy_protocol;
+        if legacy {
+            let serving = self.clone().serve_legacy();
+            tokio::spawn(async move {
+                if let Err(e) = serving.await {
+                    log::warn!("legacy protocol listener stopped: {:?}", e);
+                }
+            });
+        }
+        let (inflight, mut drained) = mpsc::channel::<()>(1);
+        loop {
+            let stream = tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("failed to accept: {}", e);
+                        continue;
+                    }
+                },
+                _ = terminate.recv() => {
+                    log::info!("SIGTERM received");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    log::info!("SIGINT received");
+                    break;
+                }
+                _ = hangup.recv() => {
+                    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Reloading]);
+                    if let Err(e) = self.reload() {
+                        log::warn!("failed to reload config, keeping the current one: {:?}", e);
+                    }
+                    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+                    continue;
+                }
+            };
+            let daemon = self.clone();
+            let inflight = inflight.clone();
+            tokio::spawn(async move {
+                let _inflight = inflight;
+                daemon.serve(stream).await;
+            });
+        }
+        let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+        drop(inflight);
+        if tokio::time::timeout(DRAIN_TIMEOUT, drained.recv()).await.is_err() {
+            log::warn!("gave up waiting for in-flight requests");
+        }
+        if !activated {
+            fs::remove_file(&self.socket_conf.socket_path)?;
+        }
+        if legacy {
+            fs::remove_file(legacy_socket_path()).unwrap_or_default();
+        }
+        fs::remove_file(&self.socket_conf.snapshot_path).unwrap_or_default();
+        log::info!("Stopped");
+        Ok(())
+    }
+
+    /// Answers the requests of one connection until the client hangs up
+    async fn serve(&self, mut stream: UnixStream) {
+        let caller = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials).ok();
+        loop {
+            let req = match tokio::time::timeout(IDLE_TIMEOUT, read_frame(&mut stream)).await {
+                Ok(Ok(Some(req))) => req,
+                Ok(Ok(None)) | Err(_) => break,
+                Ok(Err(e)) => {
+                    log::warn!("failed to read a request from {:?}: {}", caller, e);
+                    break;
+                }
+            };
+            let response = self.respond(&req, caller).await;
+            if let Err(e) = write_frame(&mut stream, &response).await {
+                log::warn!("failed to send back to {:?}: {}", caller, e);
+                break;
+            }
+        }
+    }
+
+    /// Answers a JSON request in the protocol version it was sent with
+    async fn respond(&self, req: &[u8], caller: Option<UnixCredentials>) -> Vec<u8> {
+        let (version, id, message) = match serde_json::from_slice::<Request>(req) {
+            Ok(req) if req.version < MIN_PROTOCOL_VERSION || req.version > PROTOCOL_VERSION => {
+                let message = format!("protocol version {} is not supported", req.version);
+                (PROTOCOL_VERSION, req.id, DaemonMessage::error(ErrorCode::UnsupportedVersion, &message))
+            }
+            Ok(req) => (req.version, req.id, self.dispatch(req.message, caller).await),
+            Err(e) => {
+                log::warn!("malformed request from {:?}: {}", caller, e);
+                (PROTOCOL_VERSION, 0, DaemonMessage::error(ErrorCode::Malformed, "malformed message"))
+            }
+        };
+        log::debug!("-> response #{}: {}", id, message);
+        let response = Response { version,
+                                  id,
+                                  message };
+        serde_json::to_vec(&response).unwrap_or_default()
+    }
+
+    /// Serves older clients, which send colon-delimited datagrams to `$TMPDIR/sectorad`, while `legacy_protocol` is on
+    async fn serve_legacy(self: Arc<Self>) -> Result<(), Error> {
+        let socket_path = legacy_socket_path();
+        fs::remove_file(&socket_path).unwrap_or_default();
+        let mut socket = tokio::net::UnixDatagram::bind(&socket_path)?;
+        fs::set_permissions(&socket_path, unix::fs::PermissionsExt::from_mode(0o666)).unwrap_or_default();
+        // NOTE: older clients bind their own end in `$TMPDIR/sectora`, which older daemons used to create
+        let client_dir = std::env::temp_dir().join("sectora");
+        if fs::DirBuilder::new().mode(0o1777).create(&client_dir).is_ok() {
+            fs::set_permissions(&client_dir, unix::fs::PermissionsExt::from_mode(0o1777)).unwrap_or_default();
+        }
+        log::info!("Serving the legacy protocol @ {}", &socket_path);
+        // NOTE: replies go out through the listening socket, as older clients only read from the address they sent to
+        let (replies, mut outbox) = mpsc::channel::<(Vec<u8>, PathBuf)>(64);
+        let mut buf = [0u8; 4096];
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (recv_cnt, src) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            log::warn!("failed to receive a legacy request: {}", e);
+                            continue;
+                        }
+                    };
+                    let src = match src.as_pathname() {
+                        Some(src) => src.to_path_buf(),
+                        None => continue,
+                    };
+                    let req = buf[..recv_cnt].to_vec();
+                    let daemon = self.clone();
+                    let mut replies = replies.clone();
+                    tokio::spawn(async move {
+                        let response = daemon.respond_legacy(&req, &src).await;
+                        replies.send((response, src)).await.unwrap_or_default();
+                    });
+                }
+                Some((response, dst)) = outbox.recv() => {
+                    if let Err(e) = socket.send_to(&response, &dst).await {
+                        log::warn!("failed to send back to the client {:?}:{}", dst, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers the colon-delimited text of older clients; datagrams carry no peer credentials,
+    /// so privileged requests are refused here
+    async fn respond_legacy(&self, req: &[u8], src: &Path) -> Vec<u8> {
+        let message = if !self.client().conf.legacy_protocol {
+            log::warn!("legacy request from {:?} refused", src);
+            DaemonMessage::error(ErrorCode::UnsupportedVersion, "legacy protocol is disabled")
+        } else {
+            match std::str::from_utf8(req).map(str::parse::<ClientMessage>) {
+                Ok(Ok(msg)) => self.dispatch(msg, None).await,
+                _ => {
+                    log::warn!("malformed message from {:?}", src);
+                    DaemonMessage::error(ErrorCode::Malformed, "malformed message")
+                }
+            }
+        };
+        log::debug!("-> response: {}", message);
+        message.to_string().into_bytes()
+    }
+
+    async fn dispatch(&self, msg: ClientMessage, caller: Option<UnixCredentials>) -> DaemonMessage {
+        log::debug!("recv: {}, caller:{:?}", msg, caller);
+        if requires_privilege(&msg) && !self.is_privileged(caller) {
+            log::warn!("denied {} for {:?}", msg, caller);
+            DaemonMessage::error(ErrorCode::PermissionDenied, "permission denied")
+        } else {
+            self.handle(&msg, caller.map(|c| c.uid())).await
+        }
     }
 
-    async fn run(&mut self) -> Result<(), Error> {
-        let rl = self.client.get_rate_limit().await.expect("get rate limit");
-        log::info!("Rate Limit: {:?}", rl);
-        let sectors = self.client.get_sectors().await.expect("get sectors");
+    fn client(&self) -> Arc<GithubClient> { ghclient::current(&self.client) }
+
+    /// Re-reads the config and swaps the client; the running one is kept if the new config is invalid
+    fn reload(&self) -> Result<(), Error> {
+        let config = Config::from_path(&(*CONF_PATH))?;
+        config.validate()?;
+        *self.negcache() = NegativeCache::new(config.negative_cache_ttl, config.negative_cache_size);
+        let client = Arc::new(GithubClient::new(&config, self.degraded.clone()));
+        *self.client.write().unwrap_or_else(|e| e.into_inner()) = client;
+        log::info!("Config reloaded from {:?}", *CONF_PATH);
+        Ok(())
+    }
+
+    /// Checks GitHub once the socket is up, so that a black-holed GitHub cannot hold back READY
+    async fn warm_up(self: Arc<Self>) {
+        match self.client().get_rate_limit().await {
+            Ok(rl) => log::info!("Rate Limit: {:?}", rl),
+            Err(e) => {
+                log::warn!("GitHub is unreachable, starting in degraded mode: {:?}", e);
+                self.degraded.store(true, Ordering::SeqCst);
+                tokio::spawn(self.clone().recover());
+            }
+        }
+        let sectors = self.get_sectors().await;
         log::info!("{} sector[s] loaded", sectors.len());
-        let socket = unix::net::UnixDatagram::bind(&self.socket_conf.socket_path)?;
-        fs::set_permissions(&self.socket_conf.socket_path,
-                            unix::fs::PermissionsExt::from_mode(0o666)).unwrap_or_default();
-        let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
-        log::info!("Start running @ {}", &self.socket_conf.socket_path);
+    }
+
+    /// Retries GitHub with backoff until it answers, then leaves degraded mode
+    async fn recover(self: Arc<Self>) {
+        let mut backoff = RETRY_MIN;
         loop {
-            let mut buf = [0u8; 4096];
-            let (recv_cnt, src) = socket.recv_from(&mut buf)?;
-            let msgstr = String::from_utf8(buf[..recv_cnt].to_vec()).expect("decode msg str");
-            log::debug!("recv: {}, src:{:?}", msgstr, src);
-            let response = self.handle(&msgstr.parse::<ClientMessage>().expect("parse ClientMessage"))
-                               .await;
-            log::debug!("-> response: {}", response);
-            match socket.send_to(&response.to_string().as_bytes(), src.as_pathname().expect("src")) {
-                Ok(sendsize) => log::debug!("send: {}", sendsize),
-                Err(err) => log::warn!("failed to send back to the client {:?}:{}", src, err),
+            tokio::time::delay_for(backoff).await;
+            match self.client().get_rate_limit().await {
+                Ok(rl) => {
+                    log::info!("GitHub is reachable again, Rate Limit: {:?}", rl);
+                    break;
+                }
+                Err(e) => log::debug!("GitHub is still unreachable: {:?}", e),
             }
+            backoff = std::cmp::min(backoff * 2, RETRY_MAX);
+        }
+        self.degraded.store(false, Ordering::SeqCst);
+        let sectors = self.get_sectors().await;
+        self.notify_status(&sectors);
+    }
+
+    fn notify_status(&self, sectors: &[SectorGroup]) {
+        let members: usize = sectors.iter().map(|s| s.members.len()).sum();
+        let mut status = format!("{} sector[s], {} member[s] loaded", sectors.len(), members);
+        if self.degraded.load(Ordering::SeqCst) {
+            status = format!("degraded, serving cache: {}", status);
         }
+        let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Status(status)]);
     }
 
-    async fn handle(&mut self, msg: &ClientMessage) -> DaemonMessage {
+    /// Root and members of `admin_gid` may read shadow entries and manage caches
+    fn is_privileged(&self, caller: Option<UnixCredentials>) -> bool {
+        let caller = match caller {
+            Some(caller) => caller,
+            None => return false,
+        };
+        if caller.uid() == 0 {
+            return true;
+        }
+        match self.client().conf.admin_gid {
+            Some(gid) => caller.gid() == gid || supplementary_gids(caller.pid()).contains(&gid),
+            None => false,
+        }
+    }
+
+    fn cursors(&self) -> MutexGuard<'_, Cursors> { self.cursors.lock().unwrap_or_else(|e| e.into_inner()) }
+
+    fn negcache(&self) -> MutexGuard<'_, NegativeCache> { self.negcache.lock().unwrap_or_else(|e| e.into_inner()) }
+
+    /// `uid` is that of the caller, `None` on the legacy socket
+    async fn handle(&self, msg: &ClientMessage, uid: Option<u32>) -> DaemonMessage {
         match msg {
-            ClientMessage::Key { user } => match self.client.get_user_public_key(&user).await {
-                Ok(keys) => DaemonMessage::Key { keys },
-                Err(_) => DaemonMessage::Error { message: String::from("get key failed") },
-            },
-            ClientMessage::Pam { user } => match self.client.check_pam(&user).await {
-                Ok(result) => DaemonMessage::Pam { result },
-                Err(_) => DaemonMessage::Error { message: String::from("check pam failed") },
+            ClientMessage::Key { user } => {
+                if !self.is_member(&user).await {
+                    return DaemonMessage::error(ErrorCode::NotFound, "not found");
+                }
+                if let Some(keys) = self.keys.read().unwrap_or_else(|e| e.into_inner()).get(user) {
+                    return DaemonMessage::Key { keys: keys.clone() };
+                }
+                let client = self.client();
+                let policy = client.conf.offline_of(&user, &self.get_sectors().await);
+                match client.get_user_public_key(&user, policy).await {
+                    Ok(keys) => {
+                        self.keys
+                            .write()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(user.clone(), keys.clone());
+                        DaemonMessage::Key { keys }
+                    }
+                    Err(_) => DaemonMessage::error(ErrorCode::Unavailable, "get key failed"),
+                }
+            }
+            ClientMessage::Hello { version } if *version >= MIN_PROTOCOL_VERSION => {
+                DaemonMessage::Hello { version: std::cmp::min(*version, PROTOCOL_VERSION) }
+            }
+            ClientMessage::Hello { version } => {
+                DaemonMessage::error(ErrorCode::UnsupportedVersion,
+                                     &format!("protocol version {} is not supported", version))
+            }
+            ClientMessage::Pam { user } => DaemonMessage::Pam { result: self.is_member(&user).await },
+            ClientMessage::CleanUp => match self.client().clear_all_caches().await {
+                Ok(_) => DaemonMessage::Success,
+                Err(_) => DaemonMessage::error(ErrorCode::Unavailable, "clean up failed"),
             },
-            ClientMessage::CleanUp => match self.client.clear_all_caches().await {
+            ClientMessage::Reload => match self.reload() {
                 Ok(_) => DaemonMessage::Success,
-                Err(_) => DaemonMessage::Error { message: String::from("clean up failed") },
+                Err(e) => DaemonMessage::error(ErrorCode::Other, &format!("reload failed: {:?}", e)),
             },
-            ClientMessage::RateLimit => match self.client.get_rate_limit().await {
+            ClientMessage::RateLimit => match self.client().get_rate_limit().await {
                 Ok(rl) => DaemonMessage::RateLimit { limit: rl.rate.limit,
                                                      remaining: rl.rate.remaining,
                                                      reset: rl.rate.reset },
-                Err(_) => DaemonMessage::Error { message: String::from("clean up failed") },
+                Err(_) => DaemonMessage::error(ErrorCode::Unavailable, "clean up failed"),
             },
-            ClientMessage::SectorGroups => match self.client.get_sectors().await {
+            ClientMessage::SectorGroups => match self.client().get_sectors().await {
                 Ok(sectors) => DaemonMessage::SectorGroups { sectors },
-                Err(_) => DaemonMessage::Error { message: String::from("get sectors failed") },
+                Err(_) => DaemonMessage::error(ErrorCode::Unavailable, "get sectors failed"),
             },
-            ClientMessage::Pw(pw) => self.handle_pw(pw).await,
-            ClientMessage::Sp(sp) => self.handle_sp(sp).await,
-            ClientMessage::Gr(gr) => self.handle_gr(gr).await,
+            ClientMessage::Pw(pw) => self.handle_pw(pw, uid).await,
+            ClientMessage::Sp(sp) => self.handle_sp(sp, uid).await,
+            ClientMessage::Gr(gr) => self.handle_gr(gr, uid).await,
+            ClientMessage::Sg(sg) => self.handle_sg(sg, uid).await,
+            ClientMessage::Netgr(netgr) => self.handle_netgr(netgr, uid).await,
         }
     }
 
-    fn get_msg(&mut self, pid: u32) -> DaemonMessage {
-        match self.msg_cache.entry(pid) {
-            Entry::Occupied(mut o) => match o.get_mut().pop_front() {
-                Some(msg) => msg,
-                None => DaemonMessage::Error { message: String::from("not found") },
-            },
-            Entry::Vacant(_) => DaemonMessage::Error { message: String::from("not found") },
+    async fn get_sectors(&self) -> Vec<SectorGroup> { self.refresh_sectors().await.0 }
+
+    /// Gets the sectors and tells whether membership changed, in which case the snapshot is already republished
+    async fn refresh_sectors(&self) -> (Vec<SectorGroup>, bool) {
+        let sectors = self.client().get_sectors().await.unwrap_or_default();
+        let snapshot = snapshot_id(&sectors);
+        let changed = self.snapshot.swap(snapshot, Ordering::SeqCst) != snapshot;
+        if changed {
+            log::debug!("membership snapshot changed, clearing negative cache");
+            self.negcache().clear();
+            self.notify_status(&sectors);
+            self.publish_snapshot(&sectors);
         }
+        (sectors, changed)
     }
 
-    fn clear_cache(&mut self, pid: u32) -> DaemonMessage {
-        self.msg_cache.remove(&pid).unwrap_or_default();
-        DaemonMessage::Success
+    /// Refetches the sectors whose caches a webhook dropped, so that the snapshot follows at once
+    async fn refresh_on_webhook(self: Arc<Self>, mut refreshes: mpsc::Receiver<()>) {
+        while refreshes.recv().await.is_some() {
+            log::debug!("sectors invalidated by webhook, clearing negative cache");
+            self.negcache().clear();
+            let sectors = self.get_sectors().await;
+            log::info!("{} sector[s] refetched", sectors.len());
+        }
+    }
+
+    /// Writes the passwd and group view for the NSS module to read without asking
+    fn publish_snapshot(&self, sectors: &[SectorGroup]) {
+        let mut passwd = Vec::new();
+        for sector in sectors {
+            for member in sector.members.values() {
+                let (home, sh) = self.get_home_sh(&member.login);
+                passwd.push(PwEntry { login: member.login.clone(),
+                                      uid: member.id,
+                                      gid: sector.get_gid(),
+                                      home,
+                                      sh,
+                                      gecos: self.get_gecos(&member.login) });
+            }
+        }
+        let snapshot = Snapshot { passwd,
+                                  groups: sectors.to_vec() };
+        if let Err(e) = snapshot.publish(&self.socket_conf.snapshot_path) {
+            log::warn!("failed to publish snapshot: {:?}", e);
+        }
+    }
+
+    /// Republishes the snapshot now and then, so that readers can tell it from one left by a stopped daemon
+    async fn publish_snapshots(self: Arc<Self>) {
+        loop {
+            tokio::time::delay_for(snapshot::PUBLISH_INTERVAL).await;
+            let (sectors, changed) = self.refresh_sectors().await;
+            if !changed {
+                self.publish_snapshot(&sectors);
+            }
+        }
+    }
+
+    async fn is_member(&self, login: &str) -> bool {
+        let miss = Miss::Login(String::from(login));
+        if self.negcache().contains(&miss) {
+            return false;
+        }
+        if self.get_sectors().await.iter().any(|s| s.members.contains_key(login)) {
+            return true;
+        }
+        self.negcache().insert(miss);
+        false
+    }
+
+    fn open_cursor(&self, token: u64, opener: Opener, ents: VecDeque<DaemonMessage>) -> DaemonMessage {
+        match self.cursors().open(token, opener, ents) {
+            Ok(_) => DaemonMessage::Success,
+            Err(Refused) => cursor_refused(token, opener),
+        }
+    }
+
+    fn get_msg(&self, token: u64, opener: Opener) -> DaemonMessage {
+        match self.cursors().next(token, opener) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => DaemonMessage::error(ErrorCode::NotFound, "not found"),
+            Err(Refused) => cursor_refused(token, opener),
+        }
+    }
+
+    fn clear_cache(&self, token: u64, opener: Opener) -> DaemonMessage {
+        match self.cursors().close(token, opener) {
+            Ok(_) => DaemonMessage::Success,
+            Err(Refused) => cursor_refused(token, opener),
+        }
+    }
+
+    fn get_gecos(&self, login: &str) -> String {
+        let policy = self.client().conf.gecos;
+        match self.profiles.read().unwrap_or_else(|e| e.into_inner()).get(login) {
+            Some(profile) => profile.gecos(policy),
+            None => String::new(),
+        }
     }
 
     fn get_home_sh(&self, login: &str) -> (String, String) {
-        let conf = &self.client.conf;
+        let client = self.client();
+        let conf = &client.conf;
         let home = conf.home.replace("{}", login);
         let sh: String = match UserConfig::from_path(&Path::new(&home).join(&conf.user_conf_path)) {
             Ok(personal) => match personal.sh {
@@ -156,22 +574,50 @@ impl Daemon {
         (home, sh)
     }
 
-    fn get_pass(&self, login: &str) -> String {
-        let home = self.client.conf.home.replace("{}", login);
-        let pass: String = match UserConfig::from_path(&Path::new(&home).join(&self.client.conf.user_conf_path)) {
+    /// The password of the member's own config, changed on the day the daemon first saw it, and the configured aging
+    fn get_shadow(&self, login: &str, sectors: &[SectorGroup]) -> (String, Aging) {
+        let client = self.client();
+        let conf = &client.conf;
+        let home = conf.home.replace("{}", login);
+        let path = Path::new(&home).join(&conf.user_conf_path);
+        let pass: String = match UserConfig::from_path(&path) {
             Ok(personal) => match personal.pass {
                 Some(pass) => pass,
                 None => String::from("*"),
             },
             Err(_) => String::from("*"),
         };
-        pass
+        let expire = conf.expire_of(login, sectors).unwrap_or(-1);
+        // NOTE: without a password there is nothing to age, and a `max` would only lock out key logins
+        if pass == "*" {
+            return (pass, Aging { expire, ..Aging::default() });
+        }
+        let lstchg = match PasswordChanges::new(&conf.cache_dir).changed_on(login, &pass) {
+            Ok(day) => day,
+            Err(e) => {
+                log::warn!("failed to record the password change of {}: {:?}", login, e);
+                -1
+            }
+        };
+        let aging = Aging { lstchg,
+                            min: conf.aging.min.unwrap_or(-1),
+                            max: conf.aging.max.unwrap_or(-1),
+                            warn: conf.aging.warn.unwrap_or(-1),
+                            inact: conf.aging.inact.unwrap_or(-1),
+                            expire };
+        (pass, aging)
     }
 
-    async fn handle_pw(&mut self, pw: &Pw) -> DaemonMessage {
+    async fn handle_pw(&self, pw: &Pw, uid: Option<u32>) -> DaemonMessage {
+        let opener = Opener { database: Database::Passwd,
+                              uid };
         match pw {
             Pw::Uid(uid) => {
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                let miss = Miss::Uid(*uid);
+                if self.negcache().contains(&miss) {
+                    return DaemonMessage::error(ErrorCode::NotFound, "not found");
+                }
+                for sector in self.get_sectors().await {
                     for member in sector.members.values() {
                         if uid == &member.id {
                             let (home, sh) = self.get_home_sh(&member.login);
@@ -179,13 +625,19 @@ impl Daemon {
                                                        uid: *uid,
                                                        gid: sector.get_gid(),
                                                        home,
-                                                       sh };
+                                                       sh,
+                                                       gecos: self.get_gecos(&member.login) };
                         }
                     }
                 }
+                self.negcache().insert(miss);
             }
             Pw::Nam(name) => {
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                let miss = Miss::Login(name.clone());
+                if self.negcache().contains(&miss) {
+                    return DaemonMessage::error(ErrorCode::NotFound, "not found");
+                }
+                for sector in self.get_sectors().await {
                     for member in sector.members.values() {
                         if name == &member.login {
                             let (home, sh) = self.get_home_sh(&member.login);
@@ -193,90 +645,346 @@ impl Daemon {
                                                        uid: member.id,
                                                        gid: sector.get_gid(),
                                                        home,
-                                                       sh };
+                                                       sh,
+                                                       gecos: self.get_gecos(&member.login) };
                         }
                     }
                 }
+                self.negcache().insert(miss);
             }
-            Pw::Ent(Ent::Set(pid)) => {
+            Pw::Ent(Ent::Set(token)) => {
                 let mut ents = VecDeque::new();
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                for sector in self.get_sectors().await {
                     for member in sector.members.values() {
                         let (home, sh) = self.get_home_sh(&member.login);
                         let pw = DaemonMessage::Pw { login: member.login.clone(),
                                                      uid: member.id,
                                                      gid: sector.get_gid(),
                                                      home,
-                                                     sh };
+                                                     sh,
+                                                     gecos: self.get_gecos(&member.login) };
                         ents.push_back(pw);
                     }
                 }
-                self.msg_cache.insert(*pid, ents).unwrap_or_default();
-                return DaemonMessage::Success;
+                return self.open_cursor(*token, opener, ents);
             }
-            Pw::Ent(Ent::Get(pid)) => return self.get_msg(*pid),
-            Pw::Ent(Ent::End(pid)) => return self.clear_cache(*pid),
+            Pw::Ent(Ent::Get(token)) => return self.get_msg(*token, opener),
+            Pw::Ent(Ent::End(token)) => return self.clear_cache(*token, opener),
         }
-        DaemonMessage::Error { message: String::from("not found") }
+        DaemonMessage::error(ErrorCode::NotFound, "not found")
     }
 
-    async fn handle_sp(&mut self, sp: &Sp) -> DaemonMessage {
+    async fn handle_sp(&self, sp: &Sp, uid: Option<u32>) -> DaemonMessage {
+        let opener = Opener { database: Database::Shadow,
+                              uid };
         match sp {
             Sp::Nam(name) => {
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                let miss = Miss::Login(name.clone());
+                if self.negcache().contains(&miss) {
+                    return DaemonMessage::error(ErrorCode::NotFound, "not found");
+                }
+                let sectors = self.get_sectors().await;
+                for sector in &sectors {
                     if let Some(member) = sector.members.get(name) {
-                        let pass = self.get_pass(name);
+                        let (pass, aging) = self.get_shadow(name, &sectors);
                         return DaemonMessage::Sp { login: member.login.clone(),
-                                                   pass };
+                                                   pass,
+                                                   aging };
                     }
                 }
+                self.negcache().insert(miss);
             }
-            Sp::Ent(Ent::Set(pid)) => {
+            Sp::Ent(Ent::Set(token)) => {
                 let mut ents = VecDeque::new();
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                let sectors = self.get_sectors().await;
+                for sector in &sectors {
                     for member in sector.members.values() {
-                        let pass = self.get_pass(&member.login);
+                        let (pass, aging) = self.get_shadow(&member.login, &sectors);
                         let sp = DaemonMessage::Sp { login: member.login.clone(),
-                                                     pass };
+                                                     pass,
+                                                     aging };
                         ents.push_back(sp);
                     }
                 }
-                self.msg_cache.insert(*pid, ents).unwrap_or_default();
-                return DaemonMessage::Success;
+                return self.open_cursor(*token, opener, ents);
             }
-            Sp::Ent(Ent::Get(pid)) => return self.get_msg(*pid),
-            Sp::Ent(Ent::End(pid)) => return self.clear_cache(*pid),
+            Sp::Ent(Ent::Get(token)) => return self.get_msg(*token, opener),
+            Sp::Ent(Ent::End(token)) => return self.clear_cache(*token, opener),
         }
-        DaemonMessage::Error { message: String::from("not found") }
+        DaemonMessage::error(ErrorCode::NotFound, "not found")
     }
 
-    async fn handle_gr(&mut self, gr: &Gr) -> DaemonMessage {
+    async fn handle_gr(&self, gr: &Gr, uid: Option<u32>) -> DaemonMessage {
+        let opener = Opener { database: Database::Group,
+                              uid };
         match gr {
             Gr::Gid(gid) => {
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                for sector in self.get_sectors().await {
                     if gid == &sector.get_gid() {
                         return DaemonMessage::Gr { sector };
                     }
                 }
             }
             Gr::Nam(name) => {
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                for sector in self.get_sectors().await {
                     if name == &sector.get_group() {
                         return DaemonMessage::Gr { sector };
                     }
                 }
             }
-            Gr::Ent(Ent::Set(pid)) => {
+            Gr::Ent(Ent::Set(token)) => {
                 let mut ents = VecDeque::new();
-                for sector in self.client.get_sectors().await.unwrap_or_default() {
+                for sector in self.get_sectors().await {
                     ents.push_back(DaemonMessage::Gr { sector });
                 }
-                self.msg_cache.insert(*pid, ents).unwrap_or_default();
-                return DaemonMessage::Success;
+                return self.open_cursor(*token, opener, ents);
+            }
+            Gr::Ent(Ent::Get(token)) => return self.get_msg(*token, opener),
+            Gr::Ent(Ent::End(token)) => return self.clear_cache(*token, opener),
+        }
+        DaemonMessage::error(ErrorCode::NotFound, "not found")
+    }
+
+    async fn handle_sg(&self, sg: &Sg, uid: Option<u32>) -> DaemonMessage {
+        let opener = Opener { database: Database::Gshadow,
+                              uid };
+        match sg {
+            Sg::Nam(name) => {
+                for sector in self.get_sectors().await {
+                    if name == &sector.get_group() {
+                        let admins = self.get_admins(&sector).await;
+                        return DaemonMessage::Sg { sector, admins };
+                    }
+                }
+            }
+            Sg::Ent(Ent::Set(token)) => {
+                let mut ents = VecDeque::new();
+                for sector in self.get_sectors().await {
+                    let admins = self.get_admins(&sector).await;
+                    ents.push_back(DaemonMessage::Sg { sector, admins });
+                }
+                return self.open_cursor(*token, opener, ents);
             }
-            Gr::Ent(Ent::Get(pid)) => return self.get_msg(*pid),
-            Gr::Ent(Ent::End(pid)) => return self.clear_cache(*pid),
+            Sg::Ent(Ent::Get(token)) => return self.get_msg(*token, opener),
+            Sg::Ent(Ent::End(token)) => return self.clear_cache(*token, opener),
         }
-        DaemonMessage::Error { message: String::from("not found") }
+        DaemonMessage::error(ErrorCode::NotFound, "not found")
+    }
+
+    /// Sector groups are netgroups of `(-,login,)`, configured netgroups of `(host,-,)`
+    async fn handle_netgr(&self, netgr: &Netgr, uid: Option<u32>) -> DaemonMessage {
+        let opener = Opener { database: Database::Netgroup,
+                              uid };
+        match netgr {
+            Netgr::Set { name, token } => {
+                let triple = |host: &str, user: &str| DaemonMessage::Netgr { host: Some(String::from(host)),
+                                                                              user: Some(String::from(user)),
+                                                                              domain: None };
+                let ents: VecDeque<DaemonMessage> =
+                    match self.get_sectors().await.into_iter().find(|s| &s.get_group() == name) {
+                        Some(sector) => sector.members.values().map(|m| triple("-", &m.login)).collect(),
+                        None => match self.client().conf.netgroup.iter().find(|n| &n.name == name) {
+                            Some(netgroup) => netgroup.hosts.iter().map(|h| triple(h, "-")).collect(),
+                            None => return DaemonMessage::error(ErrorCode::NotFound, "not found"),
+                        },
+                    };
+                self.open_cursor(*token, opener, ents)
+            }
+            Netgr::Get(token) => self.get_msg(*token, opener),
+            Netgr::End(token) => self.clear_cache(*token, opener),
+        }
+    }
+
+    async fn get_admins(&self, sector: &SectorGroup) -> Vec<String> {
+        match self.client().get_sector_admins(sector).await {
+            Ok(admins) => admins,
+            Err(e) => {
+                log::warn!("failed to get admins of {}: {:?}", sector.get_group(), e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Takes over the socket systemd passes when started by sectora.socket
+fn activated_listener() -> Option<unix::net::UnixListener> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
+    for key in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(key);
+    }
+    match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == std::process::id() && fds >= 1 => {
+            let _ = fcntl(SD_LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+            Some(unsafe { unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
+        }
+        _ => None,
+    }
+}
+
+/// Where older clients look for the daemon
+fn legacy_socket_path() -> String { String::from(std::env::temp_dir().join("sectorad").to_str().unwrap_or_default()) }
+
+/// Tells whether the socket unit matches `socket_dir`; clients would not find a socket anywhere else
+fn listens_at(listener: &unix::net::UnixListener, path: &str) -> bool {
+    let addr = listener.local_addr().ok();
+    match addr.as_ref().and_then(|a| a.as_pathname()) {
+        Some(listening) if listening == Path::new(path) => true,
+        listening => {
+            log::warn!("ignoring the socket unit @ {:?}, socket_dir expects {}", listening, path);
+            false
+        }
+    }
+}
+
+/// Creates the directory of the socket, or checks that an existing one is dedicated to sectorad
+fn prepare_socket_dir(socket_path: &str) -> Result<(), Error> {
+    let dir = Path::new(socket_path).parent().unwrap_or_else(|| Path::new("/"));
+    let refuse = |why: &str| Err(Error::InvalidConfig(format!("socket_dir {:?} {}", dir, why)));
+    if !dir.is_absolute() || dir.parent().and_then(Path::parent).is_none() {
+        return refuse("must be a dedicated directory, not / or a top-level one");
+    }
+    match fs::DirBuilder::new().mode(0o755).create(dir) {
+        Ok(()) => Ok(fs::set_permissions(dir, unix::fs::PermissionsExt::from_mode(0o755))?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            let meta = fs::symlink_metadata(dir)?;
+            if !meta.is_dir() {
+                refuse("is not a directory")
+            } else if meta.uid() != nix::unistd::geteuid().as_raw() {
+                refuse("is not owned by sectorad")
+            } else if meta.mode() & 0o022 != 0 {
+                refuse("is writable by group or others")
+            } else {
+                Ok(())
+            }
+        }
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Pings the systemd watchdog at half of `WatchdogSec` while the runtime is alive
+async fn watchdog() {
+    let usec = match env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok()) {
+        Some(usec) => usec,
+        None => return,
+    };
+    if env::var("WATCHDOG_PID").map(|p| p != std::process::id().to_string())
+                               .unwrap_or(false)
+    {
+        return;
+    }
+    loop {
+        let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]);
+        tokio::time::delay_for(Duration::from_micros(usec / 2)).await;
+    }
+}
+
+/// Removes the socket left behind by a daemon that did not exit cleanly
+fn remove_stale_socket(path: &str) -> Result<(), Error> {
+    match unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(Error::Io(std::io::Error::new(std::io::ErrorKind::AddrInUse,
+                                                   format!("another sectorad is listening @ {}", path)))),
+        Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            log::warn!("removing stale socket @ {}", path);
+            Ok(fs::remove_file(path)?)
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+/// Another caller's cursor, or one of another database, is not this caller's to step through or end
+fn cursor_refused(token: u64, opener: Opener) -> DaemonMessage {
+    log::warn!("refused cursor {} to {:?}", token, opener);
+    DaemonMessage::error(ErrorCode::PermissionDenied, "cursor opened by another caller")
+}
+
+fn requires_privilege(msg: &ClientMessage) -> bool {
+    matches!(msg,
+             ClientMessage::CleanUp | ClientMessage::Reload | ClientMessage::Sp(_) | ClientMessage::Sg(_))
+}
+
+fn supplementary_gids(pid: libc::pid_t) -> Vec<u32> {
+    fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default()
+                                                       .lines()
+                                                       .filter(|l| l.starts_with("Groups:"))
+                                                       .flat_map(|l| l.split_whitespace().skip(1))
+                                                       .filter_map(|g| g.parse().ok())
+                                                       .collect()
+}
+
+/// Reads a frame (a big-endian u32 length and the payload), or `None` when the client has hung up
+async fn read_frame(stream: &mut UnixStream) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_REQUEST_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request too large"));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+async fn write_frame(stream: &mut UnixStream, payload: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    stream.write_all(payload).await
+}
+
+/// Keeps a value per member in memory, refetched every `cache_duration` while `enabled`, so that lookups never wait on
+/// GitHub; a member whose fetch fails keeps the previous value, unless the offline policy denies serving it
+async fn prefetch<T, F, Fut>(client: SharedClient, store: Arc<RwLock<HashMap<String, T>>>, what: &str,
+                             enabled: fn(&Config) -> bool, fetch: F)
+    where T: Clone,
+          F: Fn(Arc<GithubClient>, String, OfflinePolicy) -> Fut,
+          Fut: Future<Output = Result<T, Error>>
+{
+    loop {
+        let client = ghclient::current(&client);
+        if !enabled(&client.conf) {
+            store.write().unwrap_or_else(|e| e.into_inner()).clear();
+        } else {
+            match client.get_sectors().await {
+                Ok(sectors) => {
+                    let mut fetched = HashMap::new();
+                    for login in sectors.iter().flat_map(|s| s.members.keys()) {
+                        match fetch(client.clone(), login.clone(), client.conf.offline_of(login, &sectors)).await {
+                            Ok(v) => {
+                                fetched.insert(login.clone(), v);
+                            }
+                            // NOTE: past `cache_max_age` in a fail-closed sector, so the old value must go as well
+                            Err(Error::CacheExpired(url)) => {
+                                log::warn!("dropping {} of {}, cache for {} expired", what, login, url);
+                            }
+                            Err(e) => {
+                                log::warn!("failed to prefetch {} of {}: {:?}", what, login, e);
+                                if let Some(v) = store.read().unwrap_or_else(|e| e.into_inner()).get(login) {
+                                    fetched.insert(login.clone(), v.clone());
+                                }
+                            }
+                        }
+                    }
+                    log::debug!("prefetched {} of {} member[s]", what, fetched.len());
+                    *store.write().unwrap_or_else(|e| e.into_inner()) = fetched;
+                }
+                Err(e) => log::warn!("failed to refresh sectors: {:?}", e),
+            }
+        }
+        tokio::time::delay_for(Duration::from_secs(client.conf.cache_duration)).await;
+    }
+}
+
+fn snapshot_id(sectors: &[SectorGroup]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for sector in sectors {
+        sector.get_gid().hash(&mut hasher);
+        sector.get_group().hash(&mut hasher);
+        let mut members: Vec<(&String, u64)> = sector.members.values().map(|m| (&m.login, m.id)).collect();
+        members.sort();
+        members.hash(&mut hasher);
     }
+    hasher.finish()
 }
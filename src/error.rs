@@ -5,7 +5,31 @@ pub enum Error {
     Toml(toml::de::Error),
     Http(hyper::http::Error),
     Hyper(hyper::Error),
+    Nix(nix::Error),
     ParseMessageError(ParseMessageError),
+    #[allow(dead_code)]
+    CacheExpired(String),
+    #[allow(dead_code)]
+    InvalidConfig(String),
+    #[allow(dead_code)]
+    Flight(std::sync::Arc<Error>),
+}
+
+impl Error {
+    /// Whether there is no daemon to talk to, either not running or with an untrusted socket,
+    /// as opposed to being slow or busy; retrying would not help in either case
+    #[allow(dead_code)]
+    pub fn is_daemon_down(&self) -> bool {
+        match self {
+            Error::Io(e) => match e.kind() {
+                std::io::ErrorKind::NotFound
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::PermissionDenied => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 }
 
 impl From<serde_json::Error> for Error {
@@ -20,6 +44,9 @@ impl From<toml::de::Error> for Error {
 impl From<hyper::Error> for Error {
     fn from(err: hyper::Error) -> Error { Error::Hyper(err) }
 }
+impl From<nix::Error> for Error {
+    fn from(err: nix::Error) -> Error { Error::Nix(err) }
+}
 impl From<hyper::http::Error> for Error {
     fn from(err: hyper::http::Error) -> Error { Error::Http(err) }
 }
@@ -40,13 +67,24 @@ pub enum ParseSectorError {
 pub enum ParseSectorGroupError {
     Sector(ParseSectorError),
     Gid(std::num::ParseIntError),
-    Member(std::num::ParseIntError),
+    Member(ParseMemberError),
+    BadFormat,
+}
+
+#[derive(Debug)]
+pub enum ParseMemberError {
+    Id(std::num::ParseIntError),
+    #[allow(dead_code)]
+    Gid(std::num::ParseIntError),
+    BadFormat,
 }
 
 #[derive(Debug)]
 pub enum ParseMessageError {
     ParseClientMessageError,
     ParseDaemonMessageError,
+    #[allow(dead_code)]
+    UnsupportedVersion,
 }
 
 impl From<ParseMessageError> for Error {
@@ -1,27 +1,48 @@
 use crate::error::Error;
-use crate::structs::{Config, Member, PublicKey, RateLimit, Repo, Sector, SectorGroup, Team};
+use crate::structs::{
+    Config, Member, OfflinePolicy, Profile, PublicKey, RateLimit, Repo, Sector, SectorGroup, SectorType, Team,
+};
+use futures::future::{BoxFuture, FutureExt, Shared};
 use glob::glob;
 use hyper::body::HttpBody;
 use hyper::client::HttpConnector;
 use hyper::{header, Body, Client, Request};
 use hyper_tls::HttpsConnector;
+use nix::sys::stat::utimes;
+use nix::sys::time::{TimeVal, TimeValLike};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+type Flight = Shared<BoxFuture<'static, Result<String, Arc<Error>>>>;
+
+/// The client in use, swapped as a whole when the config is reloaded
+pub type SharedClient = Arc<RwLock<Arc<GithubClient>>>;
+
+pub fn current(client: &SharedClient) -> Arc<GithubClient> { client.read().unwrap_or_else(|e| e.into_inner()).clone() }
+
+#[derive(Clone)]
 pub struct GithubClient {
     client: Client<HttpsConnector<HttpConnector>>,
     pub conf: Config,
+    flights: Arc<Mutex<HashMap<String, Flight>>>,
+    degraded: Arc<AtomicBool>,
 }
 
 impl GithubClient {
-    pub fn new(config: &Config) -> GithubClient {
+    /// While `degraded` is set, contents come from the cache only; the daemon clears it once GitHub answers again
+    pub fn new(config: &Config, degraded: Arc<AtomicBool>) -> GithubClient {
         if std::env::var("SSL_CERT_FILE").is_err() {
             std::env::set_var("SSL_CERT_FILE", &config.cert_path);
         }
         let client = Client::builder().build(HttpsConnector::new());
         GithubClient { client,
-                       conf: config.clone() }
+                       conf: config.clone(),
+                       flights: Arc::new(Mutex::new(HashMap::new())),
+                       degraded }
     }
 
     fn get_cache_path(&self, url: &str) -> std::path::PathBuf {
@@ -31,6 +52,64 @@ impl GithubClient {
         path
     }
 
+    /// Marks the cache of `url` stale rather than removing it, so that it is refetched on next use
+    /// but still served under the offline policy if GitHub cannot be reached then
+    pub fn invalidate(&self, url: &str) -> Result<(), Error> { self.mark_stale(&self.get_cache_path(url)) }
+
+    /// Backdates the mtime of a cache file to just past `cache_duration`, unless it is older already
+    fn mark_stale(&self, path: &std::path::Path) -> Result<(), Error> {
+        let modified = match std::fs::metadata(path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            metadata => metadata?.modified()?,
+        };
+        let stale = SystemTime::now().checked_sub(Duration::from_secs(self.conf.cache_duration + 1))
+                                     .unwrap_or(UNIX_EPOCH);
+        if modified <= stale {
+            return Ok(());
+        }
+        let secs = stale.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let time = TimeVal::seconds(secs as i64);
+        utimes(path, &time, &time)?;
+        Ok(())
+    }
+
+    /// Marks every cache stale but those of users' keys and profiles, which no membership change touches
+    pub fn invalidate_sectors(&self) -> Result<(), Error> {
+        let users = self.get_cache_path(&format!("{}/users", self.conf.endpoint));
+        self.mark_all_stale(|path| !path.starts_with(&users))
+    }
+
+    fn mark_all_stale<F: Fn(&std::path::Path) -> bool>(&self, filter: F) -> Result<(), Error> {
+        let mut path = self.get_cache_path("");
+        path.push("**/*");
+        for entry in glob(&path.to_str().unwrap()).unwrap() {
+            match entry {
+                Ok(path) => {
+                    if path.is_file() && filter(&path) {
+                        self.mark_stale(&path)?
+                    }
+                }
+                Err(e) => println!("{:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn team_map_url(&self) -> String { format!("{}/orgs/{}/teams", self.conf.endpoint, self.conf.org) }
+
+    pub fn team_members_url(&self, tid: u64) -> String { format!("{}/teams/{}/members", self.conf.endpoint, tid) }
+
+    pub fn team_maintainers_url(&self, tid: u64) -> String {
+        format!("{}/teams/{}/members?role=maintainer", self.conf.endpoint, tid)
+    }
+
+    pub fn repo_map_url(&self) -> String { format!("{}/orgs/{}/repos", self.conf.endpoint, self.conf.org) }
+
+    pub fn repo_collaborators_url(&self, repo_name: &str) -> String {
+        format!("{}/repos/{}/{}/collaborators?affiliation=outside",
+                self.conf.endpoint, self.conf.org, repo_name)
+    }
+
     fn load_contents_from_cache(&self, url: &str) -> Result<(std::fs::Metadata, String), Error> {
         let path = self.get_cache_path(url);
         let metadata = std::fs::metadata(path.to_str().unwrap())?;
@@ -48,7 +127,30 @@ impl GithubClient {
         Ok(())
     }
 
+    /// Fetches `url`, sharing a single in-flight download among concurrent callers
     async fn get_contents_from_url(&self, url: &str) -> Result<String, Error> {
+        let flight = {
+            let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+            match flights.get(url) {
+                Some(flight) => flight.clone(),
+                None => {
+                    let client = self.clone();
+                    let key = String::from(url);
+                    let flight = async move {
+                                     let result = client.fetch_contents_from_url(&key).await.map_err(Arc::new);
+                                     client.flights.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+                                     result
+                                 }.boxed()
+                                  .shared();
+                    flights.insert(String::from(url), flight.clone());
+                    flight
+                }
+            }
+        };
+        flight.await.map_err(Error::Flight)
+    }
+
+    async fn fetch_contents_from_url(&self, url: &str) -> Result<String, Error> {
         let mut all_contents: Vec<serde_json::value::Value> = Vec::new();
         let mut page = 1;
         loop {
@@ -79,6 +181,14 @@ impl GithubClient {
     }
 
     async fn run_request(&self, req: Request<Body>) -> Result<Vec<u8>, Error> {
+        let timeout = std::time::Duration::from_secs(self.conf.request_timeout);
+        match tokio::time::timeout(timeout, self.read_response(req)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "request to GitHub timed out"))),
+        }
+    }
+
+    async fn read_response(&self, req: Request<Body>) -> Result<Vec<u8>, Error> {
         let mut resp = self.client.request(req).await?;
         let mut buff: Vec<u8> = Vec::new();
         while let Some(chunk) = resp.body_mut().data().await {
@@ -93,14 +203,23 @@ impl GithubClient {
         Ok(serde_json::from_slice(&resp)?)
     }
 
-    async fn get_contents(&self, url: &str) -> Result<String, Error> {
+    fn is_degraded(&self) -> bool { self.degraded.load(Ordering::SeqCst) }
+
+    fn unreachable(url: &str) -> Error {
+        Error::Io(std::io::Error::new(std::io::ErrorKind::NotConnected,
+                                      format!("GitHub is unreachable, no cache for {}", url)))
+    }
+
+    async fn get_contents(&self, url: &str, policy: OfflinePolicy) -> Result<String, Error> {
         match self.load_contents_from_cache(url) {
             Ok((metadata, cache_contents)) => match std::time::SystemTime::now().duration_since(metadata.modified()?) {
                 Ok(caching_duration) => {
-                    if caching_duration.as_secs() > self.conf.cache_duration {
+                    if caching_duration.as_secs() > self.conf.cache_duration && self.is_degraded() {
+                        self.stale_contents(url, cache_contents, caching_duration.as_secs(), policy)
+                    } else if caching_duration.as_secs() > self.conf.cache_duration {
                         match self.get_contents_from_url(url).await {
                             Ok(contents_from_url) => Ok(contents_from_url),
-                            Err(_) => Ok(cache_contents),
+                            Err(_) => self.stale_contents(url, cache_contents, caching_duration.as_secs(), policy),
                         }
                     } else {
                         Ok(cache_contents)
@@ -108,20 +227,63 @@ impl GithubClient {
                 }
                 Err(_) => Ok(cache_contents),
             },
+            Err(_) if self.is_degraded() => Err(Self::unreachable(url)),
             Err(_) => self.get_contents_from_url(url).await,
         }
     }
 
-    pub async fn get_user_public_key(&self, user: &str) -> Result<String, Error> {
+    fn stale_contents(&self, url: &str, cache_contents: String, age: u64, policy: OfflinePolicy)
+                      -> Result<String, Error> {
+        if age > self.conf.cache_max_age && policy == OfflinePolicy::FailClosed {
+            log::warn!("cache for {} expired ({}s old), denying", url, age);
+            return Err(Error::CacheExpired(String::from(url)));
+        }
+        log::warn!("serving stale cache for {} ({}s old)", url, age);
+        Ok(cache_contents)
+    }
+
+    /// `policy` is that of the sectors `user` is in, see `Config::offline_of`
+    pub async fn get_user_public_key(&self, user: &str, policy: OfflinePolicy) -> Result<String, Error> {
         let url = format!("{}/users/{}/keys", self.conf.endpoint, user);
-        let contents = self.get_contents(&url).await?;
+        let contents = self.get_contents(&url, policy).await?;
         let keys = serde_json::from_str::<Vec<PublicKey>>(&contents)?;
         Ok(keys.iter().map(|k| k.key.clone()).collect::<Vec<_>>().join("\n"))
     }
 
-    pub async fn check_pam(&self, user: &str) -> Result<bool, Error> {
-        let sectors = self.get_sectors().await?;
-        Ok(sectors.iter().any(|team| team.members.contains_key(user)))
+    pub async fn get_user_profile(&self, user: &str) -> Result<Profile, Error> {
+        let url = format!("{}/users/{}", self.conf.endpoint, user);
+        let contents = self.get_object(&url).await?;
+        Ok(serde_json::from_str::<Profile>(&contents)?)
+    }
+
+    /// Like `get_contents`, for an endpoint that answers a single object instead of pages of arrays
+    async fn get_object(&self, url: &str) -> Result<String, Error> {
+        // NOTE: cached beside the url rather than at it, as `{url}/keys` makes the url's own path a directory
+        let key = format!("{}.json", url);
+        let cached = self.load_contents_from_cache(&key);
+        if let Ok((metadata, contents)) = &cached {
+            let age = std::time::SystemTime::now().duration_since(metadata.modified()?)
+                                                  .map(|d| d.as_secs())
+                                                  .unwrap_or(0);
+            if age <= self.conf.cache_duration {
+                return Ok(contents.clone());
+            }
+        }
+        if self.is_degraded() {
+            return cached.map(|(_, contents)| contents).map_err(|_| Self::unreachable(url));
+        }
+        match self.fetch_object(url, &key).await {
+            Ok(contents) => Ok(contents),
+            Err(e) => cached.map(|(_, contents)| contents).map_err(|_| e),
+        }
+    }
+
+    async fn fetch_object(&self, url: &str, key: &str) -> Result<String, Error> {
+        let req = self.build_request(url)?;
+        let resp = self.run_request(req).await?;
+        let contents = serde_json::from_slice::<serde_json::Value>(&resp)?.to_string();
+        self.store_contents_to_cache(key, &contents)?;
+        Ok(contents)
     }
 
     pub async fn get_sectors(&self) -> Result<Vec<SectorGroup>, Error> {
@@ -131,59 +293,87 @@ impl GithubClient {
     }
 
     async fn get_teams_result(&self) -> Result<Vec<SectorGroup>, Error> {
-        let gh_teams = self.get_team_map(&self.conf.org).await?;
+        let gh_teams = self.get_team_map().await?;
         let mut teams = Vec::new();
         for team_conf in &self.conf.team {
             if let Some(gh_team) = gh_teams.get(&team_conf.name) {
+                let policy = team_conf.offline.unwrap_or(self.conf.offline);
+                let members = match self.get_team_members(gh_team.id, policy).await {
+                    Ok(members) => members,
+                    Err(Error::CacheExpired(_)) => continue,
+                    Err(err) => return Err(err),
+                };
                 teams.push(SectorGroup { sector: Sector::from(gh_team.clone()),
                                          gid: team_conf.gid,
                                          group: team_conf.group.clone(),
-                                         members: self.get_team_members(gh_team.id).await? });
+                                         members });
             }
         }
         Ok(teams)
     }
 
-    async fn get_team_map(&self, org: &str) -> Result<HashMap<String, Team>, Error> {
-        let url = format!("{}/orgs/{}/teams", self.conf.endpoint, org);
-        let contents = self.get_contents(&url).await?;
+    // NOTE: team and repo maps only resolve names to ids, so they may stay stale; member lists carry the policy
+    async fn get_team_map(&self) -> Result<HashMap<String, Team>, Error> {
+        let contents = self.get_contents(&self.team_map_url(), OfflinePolicy::FailOpen).await?;
         let teams = serde_json::from_str::<Vec<Team>>(&contents)?;
         Ok(teams.iter().map(|t| (t.name.clone(), t.clone())).collect())
     }
 
-    async fn get_team_members(&self, mid: u64) -> Result<HashMap<String, Member>, Error> {
-        let url = format!("{}/teams/{}/members", self.conf.endpoint, mid);
-        let contents = self.get_contents(&url).await?;
+    async fn get_team_members(&self, mid: u64, policy: OfflinePolicy) -> Result<HashMap<String, Member>, Error> {
+        let contents = self.get_contents(&self.team_members_url(mid), policy).await?;
         let members = serde_json::from_str::<Vec<Member>>(&contents)?;
         Ok(members.iter().map(|m| (m.login.clone(), m.clone())).collect())
     }
 
+    /// Logins that administer the group of `sector`: the maintainers of a team, nobody for a repo.
+    /// The maintainers are cached under the team's own `offline` policy, like its members.
+    pub async fn get_sector_admins(&self, sector: &SectorGroup) -> Result<Vec<String>, Error> {
+        match sector.sector.sector_type {
+            SectorType::Team => {
+                let policy = self.conf
+                                 .team
+                                 .iter()
+                                 .find(|t| t.name == sector.sector.name)
+                                 .and_then(|t| t.offline)
+                                 .unwrap_or(self.conf.offline);
+                let contents = self.get_contents(&self.team_maintainers_url(sector.sector.id), policy).await?;
+                let maintainers = serde_json::from_str::<Vec<Member>>(&contents)?;
+                Ok(maintainers.into_iter().map(|m| m.login).collect())
+            }
+            SectorType::Repo => Ok(Vec::new()),
+        }
+    }
+
     async fn get_repos_result(&self) -> Result<Vec<SectorGroup>, Error> {
-        let gh_repos = self.get_repo_map(&self.conf.org).await?;
+        let gh_repos = self.get_repo_map().await?;
         let mut repos = Vec::new();
         for repo_conf in &self.conf.repo {
             if let Some(gh_repo) = gh_repos.get(&repo_conf.name) {
+                let policy = repo_conf.offline.unwrap_or(self.conf.offline);
+                let members = match self.get_repo_collaborators(&gh_repo.name, policy).await {
+                    Ok(members) => members,
+                    Err(Error::CacheExpired(_)) => continue,
+                    Err(err) => return Err(err),
+                };
                 repos.push(SectorGroup { sector: Sector::from(gh_repo.clone()),
                                          gid: repo_conf.gid,
                                          group: repo_conf.group.clone(),
-                                         members: self.get_repo_collaborators(&self.conf.org, &gh_repo.name)
-                                                      .await? });
+                                         members });
             }
         }
         Ok(repos)
     }
 
-    async fn get_repo_map(&self, org: &str) -> Result<HashMap<String, Repo>, Error> {
-        let url = format!("{}/orgs/{}/repos", self.conf.endpoint, org);
-        let contents = self.get_contents(&url).await?;
+    async fn get_repo_map(&self) -> Result<HashMap<String, Repo>, Error> {
+        let contents = self.get_contents(&self.repo_map_url(), OfflinePolicy::FailOpen).await?;
         let repos = serde_json::from_str::<Vec<Repo>>(&contents)?;
         Ok(repos.iter().map(|t| (t.name.clone(), t.clone())).collect())
     }
 
-    async fn get_repo_collaborators(&self, org: &str, repo_name: &str) -> Result<HashMap<String, Member>, Error> {
-        let url = format!("{}/repos/{}/{}/collaborators?affiliation=outside",
-                          self.conf.endpoint, org, repo_name);
-        let contents = self.get_contents(&url).await?;
+    async fn get_repo_collaborators(&self, repo_name: &str, policy: OfflinePolicy)
+                                    -> Result<HashMap<String, Member>, Error> {
+        let contents = self.get_contents(&self.repo_collaborators_url(repo_name), policy)
+                           .await?;
         let members = serde_json::from_str::<Vec<Member>>(&contents)?;
         Ok(members.iter().map(|m| (m.login.clone(), m.clone())).collect())
     }
@@ -191,23 +381,10 @@ impl GithubClient {
     pub async fn get_rate_limit(&self) -> Result<RateLimit, Error> {
         let url = format!("{}/rate_limit", self.conf.endpoint);
         let req = self.build_request(&url)?;
-        let resp = futures::executor::block_on(self.run_request(req))?;
+        let resp = self.run_request(req).await?;
         Ok(serde_json::from_slice(&resp)?)
     }
 
-    pub async fn clear_all_caches(&self) -> Result<(), Error> {
-        let mut path = self.get_cache_path("");
-        path.push("**/*");
-        for entry in glob(&path.to_str().unwrap()).unwrap() {
-            match entry {
-                Ok(path) => {
-                    if path.is_file() {
-                        std::fs::remove_file(path)?
-                    }
-                }
-                Err(e) => println!("{:?}", e),
-            }
-        }
-        Ok(())
-    }
+    /// Marks every cache stale, see `invalidate`
+    pub async fn clear_all_caches(&self) -> Result<(), Error> { self.mark_all_stale(|_| true) }
 }
@@ -10,18 +10,33 @@ mod buffer;
 mod connection;
 mod cstructs;
 mod error;
+mod lookupcache;
 mod message;
+mod snapshot;
 mod statics;
 mod structs;
 
 use buffer::Buffer;
-use connection::Connection;
-use cstructs::{Group, Passwd, Spwd};
-use message::{ClientMessage as CMsg, DaemonMessage as DMsg, Ent, Gr, Pw, Sp};
+use connection::{Connection, SOCKET_CONF};
+use cstructs::{Group, Netgrent, Passwd, Sgrp, Spwd};
+use message::{ClientMessage as CMsg, DaemonMessage as DMsg, Ent, ErrorCode, Gr, Netgr, Pw, Sg, Sp};
 use nix::errno::Errno;
+use snapshot::Mapped;
+use std::collections::hash_map::RandomState;
 use std::ffi::CStr;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read};
+use std::panic::{self, AssertUnwindSafe};
 use std::process;
 use std::string::String;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::{mem, slice};
+
+static PW_SESSION: Session = Session::new();
+static SP_SESSION: Session = Session::new();
+static GR_SESSION: Session = Session::new();
+static SG_SESSION: Session = Session::new();
 
 #[allow(dead_code)]
 enum NssStatus {
@@ -29,6 +44,7 @@ enum NssStatus {
     Unavail,
     NotFound,
     Success,
+    Return,
 }
 
 impl From<NssStatus> for libc::c_int {
@@ -38,6 +54,7 @@ impl From<NssStatus> for libc::c_int {
             NssStatus::Unavail => -1,
             NssStatus::NotFound => 0,
             NssStatus::Success => 1,
+            NssStatus::Return => 2,
         }
     }
 }
@@ -47,6 +64,82 @@ fn string_from(cstrptr: *const libc::c_char) -> String {
     String::from(cstr.to_str().unwrap_or(""))
 }
 
+/// Picks an unguessable token for a new enumeration, so that no other caller can step through or end it
+fn new_session() -> u64 {
+    let mut token = [0u8; 8];
+    match File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut token)) {
+        Ok(_) => u64::from_ne_bytes(token),
+        Err(_) => {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(process::id());
+            hasher.finish()
+        }
+    }
+}
+
+/// The token of an enumeration and the process that opened it, as a forked child inherits the statics holding it
+struct Session {
+    token: AtomicU64,
+    pid: AtomicU32,
+}
+
+impl Session {
+    const fn new() -> Self {
+        Self { token: AtomicU64::new(0),
+               pid: AtomicU32::new(0) }
+    }
+
+    fn open(&self) -> u64 {
+        let token = new_session();
+        self.pid.store(process::id(), Ordering::SeqCst);
+        self.token.store(token, Ordering::SeqCst);
+        token
+    }
+
+    /// The token of this process's enumeration; one inherited from the parent does not count
+    fn current(&self) -> Option<u64> {
+        match self.token.load(Ordering::SeqCst) {
+            0 => None,
+            token if self.pid.load(Ordering::SeqCst) == process::id() => Some(token),
+            _ => None,
+        }
+    }
+
+    /// Continues this process's enumeration, or opens one of its own so as not to step through the parent's
+    fn resume(&self, conn: &Connection, set: fn(Ent) -> CMsg) -> Result<u64, error::Error> {
+        if let Some(token) = self.current() {
+            return Ok(token);
+        }
+        let token = self.open();
+        conn.communicate(set(Ent::Set(token)))?;
+        Ok(token)
+    }
+
+    fn close(&self) -> Option<u64> {
+        let token = self.current();
+        self.token.store(0, Ordering::SeqCst);
+        token
+    }
+}
+
+/// Runs an NSS entry point, turning a panic into `Unavail` rather than unwinding into the host process
+fn guard<F: FnOnce() -> libc::c_int>(errnop: *mut libc::c_int, f: F) -> libc::c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(status) => status,
+        Err(e) => {
+            let message = e.downcast_ref::<&str>()
+                           .copied()
+                           .or_else(|| e.downcast_ref::<String>().map(String::as_str))
+                           .unwrap_or("unknown");
+            log::error!("panicked: {}", message);
+            if !errnop.is_null() {
+                unsafe { *errnop = Errno::ENOENT as libc::c_int };
+            }
+            libc::c_int::from(NssStatus::Unavail)
+        }
+    }
+}
+
 macro_rules! succeed {
     () => {{
         log::debug!("Success!");
@@ -69,6 +162,10 @@ macro_rules! try_unwrap {
                 log::debug!("Ok: {:?}", ret);
                 ret
             }
+            Err(e) if e.is_daemon_down() => {
+                log::debug!("daemon is down: {:?}", e);
+                return libc::c_int::from(NssStatus::Unavail);
+            }
             Err(e) => {
                 log::debug!("failed (will retry): {:?}", e);
                 return libc::c_int::from(NssStatus::TryAgain);
@@ -78,6 +175,11 @@ macro_rules! try_unwrap {
     ($getter:expr, $err_no_p:ident) => {{
         match $getter {
             Ok(ret) => ret,
+            Err(e) if e.is_daemon_down() => {
+                log::debug!("daemon is down: {:?}", e);
+                *$err_no_p = Errno::ENOENT as libc::c_int;
+                return libc::c_int::from(NssStatus::Unavail);
+            }
             Err(e) => {
                 log::debug!("failed (will retry): {:?}", e);
                 *$err_no_p = Errno::EAGAIN as libc::c_int;
@@ -87,88 +189,102 @@ macro_rules! try_unwrap {
     }};
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn _nss_sectora_getpwnam_r(cnameptr: *const libc::c_char, pwptr: *mut Passwd,
-                                                 buf: *mut libc::c_char, buflen: libc::size_t,
-                                                 errnop: *mut libc::c_int)
-                                                 -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getpwnam_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Nam(string_from(cnameptr)))), errnop);
+/// Whether the daemon refused a privileged request, which retrying or another lookup would not change
+fn refused(msg: &DMsg) -> bool {
+    match msg {
+        DMsg::Error { code, .. } => *code == ErrorCode::PermissionDenied,
+        _ => false,
+    }
+}
+
+/// Reports how packing an entry into the caller's buffer went: a field the C struct cannot hold (an interior NUL)
+/// makes the entry unavailable, while a short buffer asks the caller to retry with a larger one
+unsafe fn packed(result: Result<(), std::io::Error>, errnop: *mut libc::c_int) -> libc::c_int {
+    match result {
+        Ok(_) => succeed!(),
+        Err(e) if e.kind() == ErrorKind::InvalidData => fail!(errnop, Errno::ENOENT, NssStatus::Unavail),
+        Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
+    }
+}
+
+/// Packs the daemon's reply to a passwd lookup, from the frame as read off the socket to the caller's struct
+unsafe fn pack_pw(reply: Result<DMsg, error::Error>, pwptr: *mut Passwd, buffer: &mut Buffer,
+                  errnop: *mut libc::c_int)
+                  -> libc::c_int {
     if let DMsg::Pw { login,
                       uid,
                       gid,
                       home,
-                      sh, } = msg
+                      sh,
+                      gecos, } = try_unwrap!(reply, errnop)
     {
-        match { (*pwptr).pack_args(&mut buffer, &login, uid, gid, &home, &sh) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
-        }
+        return packed((*pwptr).pack_args(buffer, &login, uid, gid, &home, &sh, &gecos), errnop);
     }
     fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_getpwnam_r(cnameptr: *const libc::c_char, pwptr: *mut Passwd,
+                                                 buf: *mut libc::c_char, buflen: libc::size_t,
+                                                 errnop: *mut libc::c_int)
+                                                 -> libc::c_int {
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let name = string_from(cnameptr);
+        pack_pw(lookupcache::query("_nss_sectora_getpwnam_r", CMsg::Pw(Pw::Nam(name))), pwptr, &mut buffer, errnop)
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_getpwuid_r(uid: libc::uid_t, pwptr: *mut Passwd, buf: *mut libc::c_char,
                                                  buflen: libc::size_t, errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getpwuid_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Uid(uid as u64))), errnop);
-    if let DMsg::Pw { login,
-                      uid,
-                      gid,
-                      home,
-                      sh, } = msg
-    {
-        match { (*pwptr).pack_args(&mut buffer, &login, uid, gid, &home, &sh) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
-        }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let reply = lookupcache::query("_nss_sectora_getpwuid_r", CMsg::Pw(Pw::Uid(uid as u64)));
+        pack_pw(reply, pwptr, &mut buffer, errnop)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_setpwent() -> libc::c_int {
-    let conn = try_unwrap!(Connection::new("_nss_sectora_setpwent"));
-    let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Ent(Ent::Set(process::id())))));
-    if let DMsg::Success = msg {
-        return libc::c_int::from(NssStatus::Success);
-    }
-    libc::c_int::from(NssStatus::TryAgain)
+    guard(std::ptr::null_mut(), || {
+        let conn = try_unwrap!(Connection::new("_nss_sectora_setpwent"));
+        let token = PW_SESSION.open();
+        let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Ent(Ent::Set(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_getpwent_r(pwptr: *mut Passwd, buf: *mut libc::c_char, buflen: libc::size_t,
                                                  errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getpwent_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Ent(Ent::Get(process::id())))), errnop);
-    if let DMsg::Pw { login,
-                      uid,
-                      gid,
-                      home,
-                      sh, } = msg
-    {
-        match { (*pwptr).pack_args(&mut buffer, &login, uid, gid, &home, &sh) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
-        }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getpwent_r"), errnop);
+        let token = try_unwrap!(PW_SESSION.resume(&conn, |e| CMsg::Pw(Pw::Ent(e))), errnop);
+        pack_pw(conn.communicate(CMsg::Pw(Pw::Ent(Ent::Get(token)))), pwptr, &mut buffer, errnop)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_endpwent() -> libc::c_int {
-    let conn = try_unwrap!(Connection::new("_nss_sectora_endpwent"));
-    let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Ent(Ent::End(process::id())))));
-    if let DMsg::Success = msg {
-        return libc::c_int::from(NssStatus::Success);
-    }
-    libc::c_int::from(NssStatus::TryAgain)
+    guard(std::ptr::null_mut(), || {
+        let token = match PW_SESSION.close() {
+            Some(token) => token,
+            None => return libc::c_int::from(NssStatus::Success),
+        };
+        let conn = try_unwrap!(Connection::new("_nss_sectora_endpwent"));
+        let msg = try_unwrap!(conn.communicate(CMsg::Pw(Pw::Ent(Ent::End(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
 }
 
 #[no_mangle]
@@ -176,69 +292,82 @@ pub unsafe extern "C" fn _nss_sectora_getspnam_r(cnameptr: *const libc::c_char,
                                                  buf: *mut libc::c_char, buflen: libc::size_t,
                                                  errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getspnam_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Nam(string_from(cnameptr)))), errnop);
-    if let DMsg::Sp { login, pass } = msg {
-        match { (*spptr).pack_args(&mut buffer, &login, &pass) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getspnam_r"), errnop);
+        let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Nam(string_from(cnameptr)))), errnop);
+        if let DMsg::Sp { login, pass, aging } = &msg {
+            return packed((*spptr).pack_args(&mut buffer, login, pass, aging), errnop);
         }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+        if refused(&msg) {
+            fail!(errnop, Errno::EACCES, NssStatus::Unavail)
+        }
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_setspent() -> libc::c_int {
-    let conn = try_unwrap!(Connection::new("_nss_sectora_setspent"));
-    let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Ent(Ent::Set(process::id())))));
-    if let DMsg::Success = msg {
-        return libc::c_int::from(NssStatus::Success);
-    }
-    libc::c_int::from(NssStatus::Success)
+    guard(std::ptr::null_mut(), || {
+        let conn = try_unwrap!(Connection::new("_nss_sectora_setspent"));
+        let token = SP_SESSION.open();
+        let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Ent(Ent::Set(token)))));
+        if refused(&msg) {
+            *libc::__errno_location() = libc::EACCES;
+            return libc::c_int::from(NssStatus::Unavail);
+        }
+        libc::c_int::from(NssStatus::Success)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_getspent_r(spptr: *mut Spwd, buf: *mut libc::c_char, buflen: libc::size_t,
                                                  errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getspent_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Ent(Ent::Get(process::id())))), errnop);
-    if let DMsg::Sp { login, pass } = msg {
-        match { (*spptr).pack_args(&mut buffer, &login, &pass) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getspent_r"), errnop);
+        let token = try_unwrap!(SP_SESSION.resume(&conn, |e| CMsg::Sp(Sp::Ent(e))), errnop);
+        let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Ent(Ent::Get(token)))), errnop);
+        if let DMsg::Sp { login, pass, aging } = &msg {
+            return packed((*spptr).pack_args(&mut buffer, login, pass, aging), errnop);
         }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+        if refused(&msg) {
+            fail!(errnop, Errno::EACCES, NssStatus::Unavail)
+        }
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_endspent() -> libc::c_int {
-    let conn = try_unwrap!(Connection::new("_nss_sectora_endspent"));
-    let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Ent(Ent::End(process::id())))));
-    if let DMsg::Success = msg {
-        return libc::c_int::from(NssStatus::Success);
-    }
-    libc::c_int::from(NssStatus::TryAgain)
+    guard(std::ptr::null_mut(), || {
+        let token = match SP_SESSION.close() {
+            Some(token) => token,
+            None => return libc::c_int::from(NssStatus::Success),
+        };
+        let conn = try_unwrap!(Connection::new("_nss_sectora_endspent"));
+        let msg = try_unwrap!(conn.communicate(CMsg::Sp(Sp::Ent(Ent::End(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_getgrgid_r(gid: libc::gid_t, grptr: *mut Group, buf: *mut libc::c_char,
                                                  buflen: libc::size_t, errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getgrgid_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Gid(gid as u64))), errnop);
-    if let DMsg::Gr { sector } = msg {
-        let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
-        match { (*grptr).pack_args(&mut buffer, &sector.get_group(), u64::from(gid), &members) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let msg = try_unwrap!(lookupcache::query("_nss_sectora_getgrgid_r", CMsg::Gr(Gr::Gid(gid as u64))), errnop);
+        if let DMsg::Gr { sector } = msg {
+            let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
+            return packed((*grptr).pack_args(&mut buffer, &sector.get_group(), u64::from(gid), &members), errnop);
         }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
 }
 
 #[no_mangle]
@@ -246,52 +375,454 @@ pub unsafe extern "C" fn _nss_sectora_getgrnam_r(cnameptr: *const libc::c_char,
                                                  buf: *mut libc::c_char, buflen: libc::size_t,
                                                  errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getgrnam_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Nam(string_from(cnameptr)))), errnop);
-    if let DMsg::Gr { sector } = msg {
-        let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
-        match { (*grptr).pack_args(&mut buffer, &sector.get_group(), sector.get_gid(), &members) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let name = string_from(cnameptr);
+        let msg = try_unwrap!(lookupcache::query("_nss_sectora_getgrnam_r", CMsg::Gr(Gr::Nam(name))), errnop);
+        if let DMsg::Gr { sector } = msg {
+            let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
+            return packed((*grptr).pack_args(&mut buffer, &sector.get_group(), sector.get_gid(), &members), errnop);
         }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_setgrent() -> libc::c_int {
-    let conn = try_unwrap!(Connection::new("_nss_sectora_setgrent"));
-    let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Ent(Ent::Set(process::id())))));
-    if let DMsg::Success = msg {
-        return libc::c_int::from(NssStatus::Success);
-    }
-    libc::c_int::from(NssStatus::Success)
+    guard(std::ptr::null_mut(), || {
+        let conn = try_unwrap!(Connection::new("_nss_sectora_setgrent"));
+        let token = GR_SESSION.open();
+        let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Ent(Ent::Set(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::Success)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_getgrent_r(grptr: *mut Group, buf: *mut libc::c_char, buflen: libc::size_t,
                                                  errnop: *mut libc::c_int)
                                                  -> libc::c_int {
-    let mut buffer = Buffer::new(buf, buflen);
-    let conn = try_unwrap!(Connection::new("_nss_sectora_getgrent_r"), errnop);
-    let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Ent(Ent::Get(process::id())))), errnop);
-    if let DMsg::Gr { sector } = msg {
-        let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
-        match { (*grptr).pack_args(&mut buffer, &sector.get_group(), sector.get_gid(), &members) } {
-            Ok(_) => succeed!(),
-            Err(_) => fail!(errnop, Errno::ERANGE, NssStatus::TryAgain),
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getgrent_r"), errnop);
+        let token = try_unwrap!(GR_SESSION.resume(&conn, |e| CMsg::Gr(Gr::Ent(e))), errnop);
+        let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Ent(Ent::Get(token)))), errnop);
+        if let DMsg::Gr { sector } = msg {
+            let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
+            return packed((*grptr).pack_args(&mut buffer, &sector.get_group(), sector.get_gid(), &members), errnop);
         }
-    }
-    fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _nss_sectora_endgrent() -> libc::c_int {
-    let conn = try_unwrap!(Connection::new("_nss_sectora_endgrent"));
-    let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Ent(Ent::End(process::id())))));
-    if let DMsg::Success = msg {
-        return libc::c_int::from(NssStatus::Success);
+    guard(std::ptr::null_mut(), || {
+        let token = match GR_SESSION.close() {
+            Some(token) => token,
+            None => return libc::c_int::from(NssStatus::Success),
+        };
+        let conn = try_unwrap!(Connection::new("_nss_sectora_endgrent"));
+        let msg = try_unwrap!(conn.communicate(CMsg::Gr(Gr::Ent(Ent::End(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
+}
+
+/// Walks the daemon's groups for those `login` belongs to, for when there is no snapshot to read.
+/// The enumeration is one of its own, so as not to step through one the caller has open.
+fn groups_from_daemon(login: &str) -> Result<Vec<u64>, error::Error> {
+    let conn = Connection::new("_nss_sectora_initgroups_dyn")?;
+    let token = new_session();
+    conn.communicate(CMsg::Gr(Gr::Ent(Ent::Set(token))))?;
+    let mut gids = Vec::new();
+    while let DMsg::Gr { sector } = conn.communicate(CMsg::Gr(Gr::Ent(Ent::Get(token))))? {
+        if sector.members.contains_key(login) {
+            gids.push(sector.get_gid());
+        }
+    }
+    conn.communicate(CMsg::Gr(Gr::Ent(Ent::End(token))))?;
+    Ok(gids)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_initgroups_dyn(cuserptr: *const libc::c_char, group: libc::gid_t,
+                                                     start: *mut libc::c_long, size: *mut libc::c_long,
+                                                     groupsp: *mut *mut libc::gid_t, limit: libc::c_long,
+                                                     errnop: *mut libc::c_int)
+                                                     -> libc::c_int {
+    guard(errnop, || {
+        let user = string_from(cuserptr);
+        let gids = match Mapped::load(&SOCKET_CONF.snapshot_path).and_then(|s| s.groups_of(&user)) {
+            Some(Some(gids)) => gids,
+            Some(None) => fail!(errnop, Errno::ENOENT, NssStatus::NotFound),
+            None => try_unwrap!(groups_from_daemon(&user), errnop),
+        };
+        for gid in gids {
+            let gid = gid as libc::gid_t;
+            if gid == group || (*start > 0 && slice::from_raw_parts(*groupsp, *start as usize).contains(&gid)) {
+                continue;
+            }
+            if *start == *size {
+                if limit > 0 && *size >= limit {
+                    break;
+                }
+                let mut grown = (*size * 2).max(1);
+                if limit > 0 {
+                    grown = grown.min(limit);
+                }
+                let groups =
+                    libc::realloc(*groupsp as *mut libc::c_void, grown as usize * mem::size_of::<libc::gid_t>());
+                if groups.is_null() {
+                    fail!(errnop, Errno::ENOMEM, NssStatus::TryAgain)
+                }
+                *groupsp = groups as *mut libc::gid_t;
+                *size = grown;
+            }
+            *(*groupsp).offset(*start as isize) = gid;
+            *start += 1;
+        }
+        succeed!()
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_getsgnam_r(cnameptr: *const libc::c_char, sgptr: *mut Sgrp,
+                                                 buf: *mut libc::c_char, buflen: libc::size_t,
+                                                 errnop: *mut libc::c_int)
+                                                 -> libc::c_int {
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getsgnam_r"), errnop);
+        let msg = try_unwrap!(conn.communicate(CMsg::Sg(Sg::Nam(string_from(cnameptr)))), errnop);
+        if let DMsg::Sg { sector, admins } = &msg {
+            let admins: Vec<&str> = admins.iter().map(String::as_str).collect();
+            let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
+            return packed((*sgptr).pack_args(&mut buffer, &sector.get_group(), &admins, &members), errnop);
+        }
+        if refused(&msg) {
+            fail!(errnop, Errno::EACCES, NssStatus::Unavail)
+        }
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_setsgent() -> libc::c_int {
+    guard(std::ptr::null_mut(), || {
+        let conn = try_unwrap!(Connection::new("_nss_sectora_setsgent"));
+        let token = SG_SESSION.open();
+        let msg = try_unwrap!(conn.communicate(CMsg::Sg(Sg::Ent(Ent::Set(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        if refused(&msg) {
+            *libc::__errno_location() = libc::EACCES;
+            return libc::c_int::from(NssStatus::Unavail);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_getsgent_r(sgptr: *mut Sgrp, buf: *mut libc::c_char, buflen: libc::size_t,
+                                                 errnop: *mut libc::c_int)
+                                                 -> libc::c_int {
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getsgent_r"), errnop);
+        let token = try_unwrap!(SG_SESSION.resume(&conn, |e| CMsg::Sg(Sg::Ent(e))), errnop);
+        let msg = try_unwrap!(conn.communicate(CMsg::Sg(Sg::Ent(Ent::Get(token)))), errnop);
+        if let DMsg::Sg { sector, admins } = &msg {
+            let admins: Vec<&str> = admins.iter().map(String::as_str).collect();
+            let members: Vec<&str> = sector.members.values().map(|m| m.login.as_str()).collect();
+            return packed((*sgptr).pack_args(&mut buffer, &sector.get_group(), &admins, &members), errnop);
+        }
+        if refused(&msg) {
+            fail!(errnop, Errno::EACCES, NssStatus::Unavail)
+        }
+        fail!(errnop, Errno::ENOENT, NssStatus::NotFound)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_endsgent() -> libc::c_int {
+    guard(std::ptr::null_mut(), || {
+        let token = match SG_SESSION.close() {
+            Some(token) => token,
+            None => return libc::c_int::from(NssStatus::Success),
+        };
+        let conn = try_unwrap!(Connection::new("_nss_sectora_endsgent"));
+        let msg = try_unwrap!(conn.communicate(CMsg::Sg(Sg::Ent(Ent::End(token)))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_setnetgrent(cnameptr: *const libc::c_char, result: *mut Netgrent)
+                                                  -> libc::c_int {
+    guard(std::ptr::null_mut(), || {
+        let conn = try_unwrap!(Connection::new("_nss_sectora_setnetgrent"));
+        let token = new_session();
+        let msg = try_unwrap!(conn.communicate(CMsg::Netgr(Netgr::Set { name: string_from(cnameptr),
+                                                                          token })));
+        if let DMsg::Success = msg {
+            (*result).set_session(token);
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::NotFound)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_getnetgrent_r(result: *mut Netgrent, buf: *mut libc::c_char,
+                                                    buflen: libc::size_t, errnop: *mut libc::c_int)
+                                                    -> libc::c_int {
+    guard(errnop, || {
+        let mut buffer = Buffer::new(buf, buflen);
+        let conn = try_unwrap!(Connection::new("_nss_sectora_getnetgrent_r"), errnop);
+        let msg = try_unwrap!(conn.communicate(CMsg::Netgr(Netgr::Get((*result).session()))), errnop);
+        if let DMsg::Netgr { host, user, domain } = msg {
+            let packing = (*result).pack_args(&mut buffer, host.as_deref(), user.as_deref(), domain.as_deref());
+            return packed(packing, errnop);
+        }
+        fail!(errnop, Errno::ENOENT, NssStatus::Return)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _nss_sectora_endnetgrent(result: *mut Netgrent) -> libc::c_int {
+    guard(std::ptr::null_mut(), || {
+        let token = (*result).session();
+        (*result).set_session(0);
+        if token == 0 {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        let conn = try_unwrap!(Connection::new("_nss_sectora_endnetgrent"));
+        let msg = try_unwrap!(conn.communicate(CMsg::Netgr(Netgr::End(token))));
+        if let DMsg::Success = msg {
+            return libc::c_int::from(NssStatus::Success);
+        }
+        libc::c_int::from(NssStatus::TryAgain)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack_passwd(name: &str, home: &str, buflen: usize) -> (libc::c_int, libc::c_int) {
+        let mut pw: Passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; buflen];
+        let mut buffer = Buffer::new(buf.as_mut_ptr(), buflen);
+        let mut errno = 0;
+        let status = unsafe { packed(pw.pack_args(&mut buffer, name, 1000, 1000, home, "/bin/sh", ""), &mut errno) };
+        (status, errno)
+    }
+
+    #[test]
+    fn packs_entry() {
+        assert_eq!(pack_passwd("alice", "/home/alice", 1024).0, libc::c_int::from(NssStatus::Success));
+    }
+
+    #[test]
+    fn interior_nul_in_name_is_unavailable() {
+        assert_eq!(pack_passwd("ali\0ce", "/home/alice", 1024),
+                   (libc::c_int::from(NssStatus::Unavail), Errno::ENOENT as libc::c_int));
+    }
+
+    #[test]
+    fn interior_nul_in_dir_is_unavailable() {
+        assert_eq!(pack_passwd("alice", "/home/ali\0ce", 1024),
+                   (libc::c_int::from(NssStatus::Unavail), Errno::ENOENT as libc::c_int));
+    }
+
+    #[test]
+    fn overlong_entry_asks_to_retry() {
+        assert_eq!(pack_passwd(&"a".repeat(64), "/home/alice", 16),
+                   (libc::c_int::from(NssStatus::TryAgain), Errno::ERANGE as libc::c_int));
+    }
+
+    #[test]
+    fn panic_in_guard_is_unavailable() {
+        let mut errno = 0;
+        assert_eq!(guard(&mut errno, || panic!("static message")), libc::c_int::from(NssStatus::Unavail));
+        assert_eq!(errno, Errno::ENOENT as libc::c_int);
+        let mut errno = 0;
+        let login = "alice";
+        assert_eq!(guard(&mut errno, || panic!("formatted message for {}", login)),
+                   libc::c_int::from(NssStatus::Unavail));
+        assert_eq!(errno, Errno::ENOENT as libc::c_int);
+        assert_eq!(guard(std::ptr::null_mut(), || panic!("no errnop")), libc::c_int::from(NssStatus::Unavail));
+    }
+
+    fn sector(id: u64, name: &str, logins: &[(&str, u64)]) -> structs::SectorGroup {
+        structs::SectorGroup { sector: structs::Sector { id,
+                                                         name: String::from(name),
+                                                         sector_type: structs::SectorType::Team },
+                               gid: None,
+                               group: None,
+                               members: logins.iter()
+                                              .map(|&(login, id)| {
+                                                  (String::from(login),
+                                                   structs::Member { id,
+                                                                     login: String::from(login) })
+                                              })
+                                              .collect() }
+    }
+
+    fn mapped(snapshot: &snapshot::Snapshot) -> std::sync::Arc<Mapped> {
+        let path = std::env::temp_dir().join(format!("sectora-snapshot-{}", process::id()));
+        let path = path.to_str().unwrap();
+        snapshot.publish(path).unwrap();
+        let file = File::open(path).unwrap();
+        let len = file.metadata().unwrap().len() as usize;
+        let mapped = Mapped::map(&file, len).unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::sync::Arc::new(mapped)
+    }
+
+    fn pw(login: &str, uid: u64, gid: u64) -> snapshot::PwEntry {
+        snapshot::PwEntry { login: String::from(login),
+                            uid,
+                            gid,
+                            home: format!("/home/{}", login),
+                            sh: String::from("/bin/bash"),
+                            gecos: String::new() }
+    }
+
+    #[test]
+    fn snapshot_answers_from_indices() {
+        let passwd = vec![pw("carol", 30, 200), pw("alice", 10, 200), pw("alice", 10, 100)];
+        let snapshot = snapshot::Snapshot { passwd,
+                                            groups: vec![sector(200, "ops", &[("carol", 30), ("alice", 10)]),
+                                                         sector(100, "dev", &[("alice", 10)])] };
+        let mapped = mapped(&snapshot);
+        match mapped.answer(&CMsg::Pw(Pw::Nam(String::from("alice")))) {
+            Some(DMsg::Pw { uid, gid, home, .. }) => assert_eq!((uid, gid, home.as_str()), (10, 200, "/home/alice")),
+            other => panic!("unexpected {:?}", other),
+        }
+        match mapped.answer(&CMsg::Pw(Pw::Uid(30))) {
+            Some(DMsg::Pw { login, .. }) => assert_eq!(login, "carol"),
+            other => panic!("unexpected {:?}", other),
+        }
+        match mapped.answer(&CMsg::Gr(Gr::Nam(String::from("ops")))) {
+            Some(DMsg::Gr { sector }) => {
+                assert_eq!(sector.get_gid(), 200);
+                assert!(sector.members.contains_key("alice") && sector.members.contains_key("carol"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match mapped.answer(&CMsg::Gr(Gr::Gid(300))) {
+            Some(DMsg::Error { code, .. }) => assert_eq!(code, ErrorCode::NotFound),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(mapped.groups_of("alice"), Some(Some(vec![100, 200])));
+        assert_eq!(mapped.groups_of("bob"), Some(None));
+    }
+
+    #[test]
+    fn truncated_snapshot_is_not_mapped() {
+        let path = std::env::temp_dir().join(format!("sectora-truncated-{}", process::id()));
+        let path = path.to_str().unwrap();
+        snapshot::Snapshot { passwd: vec![pw("alice", 10, 100)],
+                             groups: vec![sector(100, "dev", &[("alice", 10)])] }.publish(path)
+                                                                                 .unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+        assert!(Mapped::map(&File::open(path).unwrap(), len as usize - 1).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn response(version: u32, id: u64, message: DMsg) -> Vec<u8> {
+        frame(&serde_json::to_vec(&message::Response { version, id, message }).unwrap())
+    }
+
+    fn alice(login: &str) -> DMsg {
+        DMsg::Pw { login: String::from(login),
+                   uid: 1000,
+                   gid: 1000,
+                   home: String::from("/home/alice"),
+                   sh: String::from("/bin/sh"),
+                   gecos: String::new() }
+    }
+
+    /// Looks alice up over one end of a pair, while the other end reads the request and answers what `reply`
+    /// makes of its id
+    fn lookup_over_pair<F: FnOnce(u64) -> Vec<u8> + Send + 'static>(reply: F) -> (libc::c_int, libc::c_int) {
+        let (near, mut far) = std::os::unix::net::UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || {
+            let mut len = [0u8; 4];
+            far.read_exact(&mut len).unwrap();
+            let mut req = vec![0u8; u32::from_be_bytes(len) as usize];
+            far.read_exact(&mut req).unwrap();
+            let req: message::Request = serde_json::from_slice(&req).unwrap();
+            std::io::Write::write_all(&mut far, &reply(req.id)).unwrap();
+        });
+        let conn = Connection::from_stream(near);
+        let mut pw: Passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 1024];
+        let mut buffer = Buffer::new(buf.as_mut_ptr(), buf.len());
+        let mut errno = 0;
+        let reply = conn.communicate(CMsg::Pw(Pw::Nam(String::from("alice"))));
+        let status = unsafe { pack_pw(reply, &mut pw, &mut buffer, &mut errno) };
+        daemon.join().unwrap();
+        (status, errno)
+    }
+
+    #[test]
+    fn framed_entry_is_packed() {
+        let (status, _) = lookup_over_pair(|id| response(message::PROTOCOL_VERSION, id, alice("alice")));
+        assert_eq!(status, libc::c_int::from(NssStatus::Success));
+    }
+
+    #[test]
+    fn framed_entry_with_interior_nul_is_unavailable() {
+        assert_eq!(lookup_over_pair(|id| response(message::PROTOCOL_VERSION, id, alice("ali\0ce"))),
+                   (libc::c_int::from(NssStatus::Unavail), Errno::ENOENT as libc::c_int));
+    }
+
+    #[test]
+    fn malformed_frame_asks_to_retry() {
+        assert_eq!(lookup_over_pair(|_| frame(b"{\"version\":1,\"id\":")),
+                   (libc::c_int::from(NssStatus::TryAgain), Errno::EAGAIN as libc::c_int));
+    }
+
+    #[test]
+    fn mismatched_id_asks_to_retry() {
+        assert_eq!(lookup_over_pair(|id| response(message::PROTOCOL_VERSION, id + 1, alice("alice"))),
+                   (libc::c_int::from(NssStatus::TryAgain), Errno::EAGAIN as libc::c_int));
+    }
+
+    #[test]
+    fn unsupported_version_asks_to_retry() {
+        assert_eq!(lookup_over_pair(|id| response(message::PROTOCOL_VERSION + 1, id, alice("alice"))),
+                   (libc::c_int::from(NssStatus::TryAgain), Errno::EAGAIN as libc::c_int));
+    }
+
+    #[test]
+    fn oversize_length_prefix_is_not_allocated() {
+        assert_eq!(lookup_over_pair(|_| u32::MAX.to_be_bytes().to_vec()),
+                   (libc::c_int::from(NssStatus::TryAgain), Errno::EAGAIN as libc::c_int));
+    }
+
+    #[test]
+    fn ending_netgroup_without_session_does_not_ask_the_daemon() {
+        let mut result: Netgrent = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { _nss_sectora_endnetgrent(&mut result) }, libc::c_int::from(NssStatus::Success));
     }
-    libc::c_int::from(NssStatus::TryAgain)
 }
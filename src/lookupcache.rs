@@ -0,0 +1,89 @@
+use crate::connection::{Connection, SOCKET_CONF};
+use crate::error;
+use crate::message::{ClientMessage, DaemonMessage, ErrorCode};
+use crate::snapshot::Mapped;
+use std::collections::HashMap;
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TTL: Duration = Duration::from_secs(5);
+const CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref CACHE: Mutex<LookupCache> = Mutex::new(LookupCache::new());
+}
+
+/// Recent passwd and group answers of this process, so that `ls -l` does not ask the daemon for every file
+#[derive(Debug)]
+struct LookupCache {
+    pid: u32,
+    entries: HashMap<String, (Instant, DaemonMessage)>,
+}
+
+impl LookupCache {
+    fn new() -> Self {
+        Self { pid: process::id(),
+               entries: HashMap::new() }
+    }
+
+    fn get(&mut self, key: &str) -> Option<DaemonMessage> {
+        self.forget_parent();
+        match self.entries.get(key) {
+            Some((inserted, msg)) if inserted.elapsed() < TTL => Some(msg.clone()),
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: String, msg: DaemonMessage) {
+        self.forget_parent();
+        if self.entries.len() >= CAPACITY {
+            self.entries.retain(|_, (inserted, _)| inserted.elapsed() < TTL);
+        }
+        if self.entries.len() >= CAPACITY {
+            self.entries.clear();
+        }
+        self.entries.insert(key, (Instant::now(), msg));
+    }
+
+    /// A forked child starts afresh rather than trusting what its parent saw
+    fn forget_parent(&mut self) {
+        if self.pid != process::id() {
+            self.pid = process::id();
+            self.entries.clear();
+        }
+    }
+}
+
+fn cacheable(msg: &DaemonMessage) -> bool {
+    match msg {
+        DaemonMessage::Pw { .. } | DaemonMessage::Gr { .. } => true,
+        DaemonMessage::Error { code, .. } => *code == ErrorCode::NotFound,
+        _ => false,
+    }
+}
+
+/// Asks the daemon unless the same lookup was answered within the last few seconds or the daemon's snapshot answers it.
+/// The cache is skipped while locked, since a child forked while another thread held the lock could never take it.
+pub fn query(logid: &str, msg: ClientMessage) -> Result<DaemonMessage, error::Error> {
+    let key = msg.to_string();
+    if let Ok(mut cache) = CACHE.try_lock() {
+        if let Some(hit) = cache.get(&key) {
+            return Ok(hit);
+        }
+    }
+    let res = match Mapped::load(&SOCKET_CONF.snapshot_path).and_then(|s| s.answer(&msg)) {
+        Some(res) => res,
+        None => Connection::new(logid)?.communicate(msg)?,
+    };
+    if cacheable(&res) {
+        if let Ok(mut cache) = CACHE.try_lock() {
+            cache.insert(key, res.clone());
+        }
+    }
+    Ok(res)
+}
@@ -41,6 +41,8 @@ enum Command {
     /// Cleans caches up
     #[structopt(alias = "cleanup")]
     CleanUp,
+    /// Reloads configuration of the daemon
+    Reload,
     /// Get rate limit for github api
     #[structopt(alias = "ratelimit")]
     RateLimit,
@@ -73,9 +75,9 @@ fn main() -> Result<(), Error> {
     debug!("connected to socket: {:?}", conn);
 
     match command {
-        Command::Check { confpath } => match Config::from_path(&confpath) {
+        Command::Check { confpath } => match Config::from_path(&confpath).and_then(|c| c.validate()) {
             Ok(_) => return Ok(()),
-            Err(_) => return Err(Error::new(ErrorKind::Other, "check failed")),
+            Err(e) => return Err(Error::new(ErrorKind::Other, format!("check failed: {:?}", e))),
         },
         Command::Key { user } => match conn.communicate(ClientMessage::Key { user }) {
             Ok(DaemonMessage::Key { keys }) => {
@@ -98,8 +100,14 @@ fn main() -> Result<(), Error> {
             Err(_) => return Err(Error::new(ErrorKind::ConnectionRefused, "failed")),
         },
         Command::CleanUp => match conn.communicate(ClientMessage::CleanUp) {
-            Ok(_) => return Ok(()),
-            Err(_) => return Err(Error::new(ErrorKind::Other, "failed")),
+            Ok(DaemonMessage::Success) => return Ok(()),
+            Ok(DaemonMessage::Error { message, .. }) => return Err(Error::new(ErrorKind::PermissionDenied, message)),
+            _ => return Err(Error::new(ErrorKind::Other, "failed")),
+        },
+        Command::Reload => match conn.communicate(ClientMessage::Reload) {
+            Ok(DaemonMessage::Success) => return Ok(()),
+            Ok(DaemonMessage::Error { message, .. }) => return Err(Error::new(ErrorKind::InvalidData, message)),
+            _ => return Err(Error::new(ErrorKind::Other, "failed")),
         },
         Command::RateLimit => match conn.communicate(ClientMessage::RateLimit) {
             Ok(DaemonMessage::RateLimit { limit,
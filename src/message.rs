@@ -3,50 +3,97 @@ use crate::structs;
 use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug)]
+/// Version of the structured protocol, raised on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest structured protocol version the daemon still answers
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Pw {
     Uid(u64),
     Nam(String),
     Ent(Ent),
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Sp {
     Nam(String),
     Ent(Ent),
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Gr {
     Gid(u64),
     Nam(String),
     Ent(Ent),
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Sg {
+    Nam(String),
+    Ent(Ent),
+}
+
+/// Like `Ent`, except that an enumeration of a netgroup starts from its name
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Netgr {
+    Set { name: String, token: u64 },
+    Get(u64),
+    End(u64),
+}
+
+/// Steps through an enumeration, identified by the session token the NSS module picked for it at `Set`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Ent {
-    Set(u32),
-    Get(u32),
-    End(u32),
+    Set(u64),
+    Get(u64),
+    End(u64),
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum ClientMessage {
+    Hello { version: u32 },
     Key { user: String },
     Pam { user: String },
     CleanUp,
+    Reload,
     RateLimit,
     SectorGroups,
     Pw(Pw),
     Sp(Sp),
     Gr(Gr),
+    Sg(Sg),
+    Netgr(Netgr),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    PermissionDenied,
+    Malformed,
+    UnsupportedVersion,
+    Unavailable,
+    Other,
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
 pub enum DaemonMessage {
+    Hello {
+        version: u32,
+    },
     Success,
     Error {
+        code: ErrorCode,
         message: String,
     },
     Key {
@@ -69,22 +116,57 @@ pub enum DaemonMessage {
         gid: u64,
         home: String,
         sh: String,
+        #[serde(default)]
+        gecos: String,
     },
     Sp {
         login: String,
         pass: String,
+        #[serde(default)]
+        aging: structs::Aging,
     },
     Gr {
         sector: structs::SectorGroup,
     },
+    Sg {
+        sector: structs::SectorGroup,
+        admins: Vec<String>,
+    },
+    Netgr {
+        host: Option<String>,
+        user: Option<String>,
+        domain: Option<String>,
+    },
+}
+
+impl DaemonMessage {
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        DaemonMessage::Error { code,
+                               message: String::from(message) }
+    }
+}
+
+/// A request of the structured protocol; the daemon echoes `id` in its response
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub version: u32,
+    pub id: u64,
+    pub message: ClientMessage,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub version: u32,
+    pub id: u64,
+    pub message: DaemonMessage,
 }
 
 impl fmt::Display for Ent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Ent::Set(pid) => write!(f, "set|{}", pid),
-            Ent::Get(pid) => write!(f, "get|{}", pid),
-            Ent::End(pid) => write!(f, "end|{}", pid),
+            Ent::Set(token) => write!(f, "set|{}", token),
+            Ent::Get(token) => write!(f, "get|{}", token),
+            Ent::End(token) => write!(f, "end|{}", token),
         }
     }
 }
@@ -124,11 +206,14 @@ impl fmt::Display for ClientMessage {
             ClientMessage::Key { user } => write!(f, "c:key:{}", user),
             ClientMessage::Pam { user } => write!(f, "c:pam:{}", user),
             ClientMessage::CleanUp => write!(f, "c:cleanup"),
+            ClientMessage::Reload => write!(f, "c:reload"),
             ClientMessage::RateLimit => write!(f, "c:ratelimit"),
             ClientMessage::SectorGroups => write!(f, "c:sectors"),
             ClientMessage::Pw(pw) => write!(f, "c:pw:{}", pw),
             ClientMessage::Sp(sp) => write!(f, "c:sp:{}", sp),
             ClientMessage::Gr(gr) => write!(f, "c:gr:{}", gr),
+            // NOTE: only in logs; older clients never send these, so they have no text encoding
+            ClientMessage::Hello { .. } | ClientMessage::Sg(_) | ClientMessage::Netgr(_) => write!(f, "{:?}", self),
         }
     }
 }
@@ -136,7 +221,7 @@ impl fmt::Display for ClientMessage {
 impl fmt::Display for DaemonMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            DaemonMessage::Error { message } => write!(f, "d:error:{}", message),
+            DaemonMessage::Error { message, .. } => write!(f, "d:error:{}", message),
             DaemonMessage::Success => write!(f, "d:success"),
             DaemonMessage::Key { keys } => write!(f, "d:key:{}", keys),
             DaemonMessage::Pam { result } => write!(f, "d:pam:{}", result),
@@ -151,9 +236,22 @@ impl fmt::Display for DaemonMessage {
                                 uid,
                                 gid,
                                 home,
-                                sh, } => write!(f, "d:pw:{}:{}:{}:{}:{}", login, uid, gid, home, sh),
-            DaemonMessage::Sp { login, pass } => write!(f, "d:sp:{}:{}", login, pass),
+                                sh,
+                                gecos, } => write!(f, "d:pw:{}:{}:{}:{}:{}:{}", login, uid, gid, home, sh, gecos),
+            DaemonMessage::Sp { login, pass, aging } => write!(f,
+                                                               "d:sp:{}:{}:{}:{}:{}:{}:{}:{}",
+                                                               login,
+                                                               pass,
+                                                               aging.lstchg,
+                                                               aging.min,
+                                                               aging.max,
+                                                               aging.warn,
+                                                               aging.inact,
+                                                               aging.expire),
             DaemonMessage::Gr { sector } => write!(f, "d:gr:{}", sector),
+            DaemonMessage::Hello { .. } | DaemonMessage::Sg { .. } | DaemonMessage::Netgr { .. } => {
+                write!(f, "{:?}", self)
+            }
         }
     }
 }
@@ -162,11 +260,20 @@ impl FromStr for Ent {
     type Err = ParseMessageError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.starts_with("set|") {
-            Ok(Ent::Set(s.get(4..).unwrap_or_default().parse::<u32>().unwrap()))
+            Ok(Ent::Set(s.get(4..)
+                         .unwrap_or_default()
+                         .parse::<u64>()
+                         .map_err(|_| ParseMessageError::ParseClientMessageError)?))
         } else if s.starts_with("get|") {
-            Ok(Ent::Get(s.get(4..).unwrap_or_default().parse::<u32>().unwrap()))
+            Ok(Ent::Get(s.get(4..)
+                         .unwrap_or_default()
+                         .parse::<u64>()
+                         .map_err(|_| ParseMessageError::ParseClientMessageError)?))
         } else if s.starts_with("end|") {
-            Ok(Ent::End(s.get(4..).unwrap_or_default().parse::<u32>().unwrap()))
+            Ok(Ent::End(s.get(4..)
+                         .unwrap_or_default()
+                         .parse::<u64>()
+                         .map_err(|_| ParseMessageError::ParseClientMessageError)?))
         } else {
             Err(ParseMessageError::ParseClientMessageError)
         }
@@ -177,11 +284,14 @@ impl FromStr for Pw {
     type Err = ParseMessageError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.starts_with("uid=") {
-            Ok(Pw::Uid(s.get(4..).unwrap_or_default().parse::<u64>().unwrap()))
+            Ok(Pw::Uid(s.get(4..)
+                        .unwrap_or_default()
+                        .parse::<u64>()
+                        .map_err(|_| ParseMessageError::ParseClientMessageError)?))
         } else if s.starts_with("name=") {
             Ok(Pw::Nam(String::from(s.get(5..).unwrap_or_default())))
         } else if s.starts_with("ent=") {
-            Ok(Pw::Ent(s.get(4..).unwrap_or_default().parse::<Ent>().unwrap()))
+            Ok(Pw::Ent(s.get(4..).unwrap_or_default().parse::<Ent>()?))
         } else {
             Err(ParseMessageError::ParseClientMessageError)
         }
@@ -194,7 +304,7 @@ impl FromStr for Sp {
         if s.starts_with("name=") {
             Ok(Sp::Nam(String::from(s.get(5..).unwrap_or_default())))
         } else if s.starts_with("ent=") {
-            Ok(Sp::Ent(s.get(4..).unwrap_or_default().parse::<Ent>().unwrap()))
+            Ok(Sp::Ent(s.get(4..).unwrap_or_default().parse::<Ent>()?))
         } else {
             Err(ParseMessageError::ParseClientMessageError)
         }
@@ -205,11 +315,14 @@ impl FromStr for Gr {
     type Err = ParseMessageError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.starts_with("gid=") {
-            Ok(Gr::Gid(s.get(4..).unwrap_or_default().parse::<u64>().unwrap()))
+            Ok(Gr::Gid(s.get(4..)
+                        .unwrap_or_default()
+                        .parse::<u64>()
+                        .map_err(|_| ParseMessageError::ParseClientMessageError)?))
         } else if s.starts_with("name=") {
             Ok(Gr::Nam(String::from(s.get(5..).unwrap_or_default())))
         } else if s.starts_with("ent=") {
-            Ok(Gr::Ent(s.get(4..).unwrap_or_default().parse::<Ent>().unwrap()))
+            Ok(Gr::Ent(s.get(4..).unwrap_or_default().parse::<Ent>()?))
         } else {
             Err(ParseMessageError::ParseClientMessageError)
         }
@@ -225,6 +338,8 @@ impl FromStr for ClientMessage {
             Ok(ClientMessage::Pam { user: String::from(s.get(6..).unwrap_or_default()) })
         } else if s == "c:cleanup" {
             Ok(ClientMessage::CleanUp)
+        } else if s == "c:reload" {
+            Ok(ClientMessage::Reload)
         } else if s == "c:ratelimit" {
             Ok(ClientMessage::RateLimit)
         } else if s == "c:sectors" {
@@ -250,6 +365,8 @@ impl FromStr for DaemonMessage {
             Ok(DaemonMessage::Pam { result: FromStr::from_str(s.get(6..).unwrap_or("false")).unwrap_or(false) })
         } else if s == "d:success" {
             Ok(DaemonMessage::Success)
+        } else if s.starts_with("d:error:") {
+            Ok(DaemonMessage::error(ErrorCode::Other, s.get(8..).unwrap_or_default()))
         } else if s.starts_with("d:ratelimit:") {
             let fields: Vec<String> = s.get(12..)
                                        .unwrap_or_default()
@@ -284,12 +401,14 @@ impl FromStr for DaemonMessage {
             let login: String = fields[0].clone();
             let home: String = fields[3].clone();
             let sh: String = fields[4].clone();
+            let gecos: String = fields.get(5).cloned().unwrap_or_default();
             match (fields[1].parse::<u64>(), fields[2].parse::<u64>()) {
                 (Ok(uid), Ok(gid)) => Ok(DaemonMessage::Pw { login,
                                                              uid,
                                                              gid,
                                                              home,
-                                                             sh }),
+                                                             sh,
+                                                             gecos }),
                 _ => Err(ParseMessageError::ParseDaemonMessageError),
             }
         } else if s.starts_with("d:sp:") {
@@ -301,8 +420,19 @@ impl FromStr for DaemonMessage {
             if fields.len() < 2 {
                 return Err(ParseMessageError::ParseDaemonMessageError);
             }
+            let days = fields[2..].iter().map(|f| f.parse::<i64>().ok()).collect::<Option<Vec<i64>>>();
+            let aging = match days.as_ref().map(|d| d.as_slice()) {
+                Some([lstchg, min, max, warn, inact, expire]) => structs::Aging { lstchg: *lstchg,
+                                                                                  min: *min,
+                                                                                  max: *max,
+                                                                                  warn: *warn,
+                                                                                  inact: *inact,
+                                                                                  expire: *expire },
+                _ => structs::Aging::default(),
+            };
             Ok(DaemonMessage::Sp { login: fields[0].clone(),
-                                   pass: fields[1].clone() })
+                                   pass: fields[1].clone(),
+                                   aging })
         } else if s.starts_with("d:gr:") {
             match s.get(5..).unwrap_or_default().parse::<structs::SectorGroup>() {
                 Ok(sector) => Ok(DaemonMessage::Gr { sector }),
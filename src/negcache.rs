@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Miss {
+    Login(String),
+    Uid(u64),
+}
+
+/// Remembers logins and uids that are not in any sector, so that repeated probes are answered locally
+#[derive(Debug)]
+pub struct NegativeCache {
+    entries: HashMap<Miss, Instant>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: u64, capacity: usize) -> Self {
+        Self { entries: HashMap::new(),
+               ttl: Duration::from_secs(ttl),
+               capacity }
+    }
+
+    pub fn contains(&mut self, miss: &Miss) -> bool {
+        match self.entries.get(miss) {
+            Some(inserted) if inserted.elapsed() < self.ttl => true,
+            Some(_) => {
+                self.entries.remove(miss);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, miss: Miss) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            self.entries.retain(|_, inserted| inserted.elapsed() < ttl);
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.entries
+                                      .iter()
+                                      .min_by_key(|(_, inserted)| **inserted)
+                                      .map(|(m, _)| m.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(miss, Instant::now());
+    }
+
+    pub fn clear(&mut self) { self.entries.clear() }
+}
@@ -0,0 +1,49 @@
+use crate::error::Error;
+use openssl::sha::sha256;
+use std::fs::{self, DirBuilder, File};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Remembers, per login, a digest of the password and the day it was first seen, so that the last change
+/// of a shadow entry follows the password itself rather than anything its owner can touch
+pub struct PasswordChanges {
+    dir: PathBuf,
+}
+
+fn today() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| (d.as_secs() / 86400) as i64).unwrap_or(0)
+}
+
+fn digest(pass: &str) -> String { sha256(pass.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect() }
+
+impl PasswordChanges {
+    /// Keeps its records in `shadow` under the cache directory, readable by root only
+    pub fn new(cache_dir: &str) -> Self { Self { dir: Path::new(cache_dir).join("shadow") } }
+
+    /// The day `login` last changed its password, which is today when the password was never seen before
+    pub fn changed_on(&self, login: &str, pass: &str) -> Result<i64, Error> {
+        let path = self.dir.join(login);
+        let digest = digest(pass);
+        if let Ok(record) = fs::read_to_string(&path) {
+            let mut fields = record.split_whitespace();
+            if let (Some(seen), Some(day)) = (fields.next(), fields.next()) {
+                if seen == digest {
+                    if let Ok(day) = day.parse() {
+                        return Ok(day);
+                    }
+                }
+            }
+        }
+        let day = today();
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        let tmp = self.dir.join(format!(".{}.tmp", login));
+        let mut file = File::create(&tmp)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        writeln!(file, "{} {}", digest, day)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(day)
+    }
+}
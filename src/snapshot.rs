@@ -0,0 +1,459 @@
+use crate::error::Error;
+use crate::message::{ClientMessage, DaemonMessage, ErrorCode, Gr, Pw};
+use crate::structs::{Member, Sector, SectorGroup, SectorType};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{ptr, slice};
+
+/// Layout of the snapshot file, raised on incompatible changes
+pub const SNAPSHOT_VERSION: u32 = 2;
+/// How often the daemon republishes the snapshot, even when nothing changed
+#[allow(dead_code)]
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+/// Snapshots older than this are taken as left behind by a daemon that is gone
+const MAX_AGE: u64 = 3 * 60;
+const MAGIC: &[u8; 8] = b"SECTORA\0";
+
+// The file is a header followed by fixed-size records and indices, all big-endian, then the strings they point at:
+//
+//   header         magic, version (u32), passwd count (u32), publish time in unix seconds (u64),
+//                  group count (u32), membership count (u32) and length of the strings (u64)
+//   passwd         records sorted by uid: uid (u64), gid (u64), the string offsets of login, home, shell and gecos
+//                  (u32 each) and the first and count of its groups in `user groups` (u32 each)
+//   passwd names   passwd record numbers (u32) sorted by login
+//   groups         records sorted by gid: gid (u64), string offset of the name (u32) and the first and count
+//                  of its members in `members` (u32 each), padded to 24 bytes
+//   group names    group record numbers (u32) sorted by name
+//   members        passwd record numbers (u32), each group's in a row
+//   user groups    group record numbers (u32), each login's in a row
+//   strings        each a length (u32) followed by as many bytes of UTF-8
+const HEADER_LEN: usize = 40;
+const PW_LEN: usize = 40;
+const GR_LEN: usize = 24;
+const INDEX_LEN: usize = 4;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct PwEntry {
+    pub login: String,
+    pub uid: u64,
+    pub gid: u64,
+    pub home: String,
+    pub sh: String,
+    pub gecos: String,
+}
+
+/// The passwd and group view of the daemon, published as a read-only file that the NSS module maps
+/// and answers from without a round trip to the daemon
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub passwd: Vec<PwEntry>,
+    pub groups: Vec<SectorGroup>,
+}
+
+fn now() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) }
+
+#[allow(dead_code)]
+fn count(n: usize) -> Result<u32, Error> {
+    u32::try_from(n).map_err(|_| Error::from(io::Error::new(io::ErrorKind::InvalidData, "snapshot too large")))
+}
+
+/// The strings section being written, handing out the offset of each string added
+#[allow(dead_code)]
+#[derive(Default)]
+struct Strings(Vec<u8>);
+
+#[allow(dead_code)]
+impl Strings {
+    fn add(&mut self, s: &str) -> Result<u32, Error> {
+        let offset = count(self.0.len())?;
+        self.0.extend_from_slice(&count(s.len())?.to_be_bytes());
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(offset)
+    }
+}
+
+impl Snapshot {
+    /// Lays the view out as the file the NSS module maps. A login in several sectors is listed once, with the gid
+    /// of the first, and equal gids or names keep the order of the sectors, as the daemon answers the first match.
+    #[allow(dead_code)]
+    fn encode(&self, published: u64) -> Result<Vec<u8>, Error> {
+        let mut logins = HashSet::new();
+        let mut passwd: Vec<&PwEntry> = self.passwd.iter().filter(|p| logins.insert(p.login.as_str())).collect();
+        passwd.sort_by_key(|p| p.uid);
+        let pw_of: HashMap<&str, u32> =
+            passwd.iter().enumerate().map(|(i, p)| (p.login.as_str(), i as u32)).collect();
+        let mut groups: Vec<&SectorGroup> = self.groups.iter().collect();
+        groups.sort_by_key(|s| s.get_gid());
+        let group_names: Vec<String> = groups.iter().map(|s| s.get_group()).collect();
+
+        let mut members = Vec::new();
+        let mut user_groups: Vec<Vec<u32>> = vec![Vec::new(); passwd.len()];
+        let mut spans = Vec::new();
+        for (g, sector) in groups.iter().enumerate() {
+            let mut ids: Vec<u32> =
+                sector.members.keys().filter_map(|login| pw_of.get(login.as_str()).copied()).collect();
+            ids.sort();
+            spans.push((count(members.len())?, count(ids.len())?));
+            for &i in &ids {
+                user_groups[i as usize].push(g as u32);
+            }
+            members.extend(ids);
+        }
+
+        let mut strings = Strings::default();
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        out.extend_from_slice(&count(passwd.len())?.to_be_bytes());
+        out.extend_from_slice(&published.to_be_bytes());
+        out.extend_from_slice(&count(groups.len())?.to_be_bytes());
+        out.extend_from_slice(&count(members.len())?.to_be_bytes());
+        let strings_len_at = out.len();
+        out.extend_from_slice(&0u64.to_be_bytes());
+        let mut first = 0;
+        for (p, gs) in passwd.iter().zip(&user_groups) {
+            out.extend_from_slice(&p.uid.to_be_bytes());
+            out.extend_from_slice(&p.gid.to_be_bytes());
+            for s in &[&p.login, &p.home, &p.sh, &p.gecos] {
+                out.extend_from_slice(&strings.add(s)?.to_be_bytes());
+            }
+            out.extend_from_slice(&count(first)?.to_be_bytes());
+            out.extend_from_slice(&count(gs.len())?.to_be_bytes());
+            first += gs.len();
+        }
+        let mut by_login: Vec<u32> = (0..passwd.len() as u32).collect();
+        by_login.sort_by(|&a, &b| passwd[a as usize].login.cmp(&passwd[b as usize].login));
+        for i in by_login {
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+        for ((sector, name), (start, len)) in groups.iter().zip(&group_names).zip(&spans) {
+            out.extend_from_slice(&sector.get_gid().to_be_bytes());
+            out.extend_from_slice(&strings.add(name)?.to_be_bytes());
+            out.extend_from_slice(&start.to_be_bytes());
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&0u32.to_be_bytes());
+        }
+        let mut by_name: Vec<u32> = (0..groups.len() as u32).collect();
+        by_name.sort_by(|&a, &b| group_names[a as usize].cmp(&group_names[b as usize]));
+        for i in by_name.into_iter().chain(members).chain(user_groups.into_iter().flatten()) {
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+        out[strings_len_at..strings_len_at + 8].copy_from_slice(&(strings.0.len() as u64).to_be_bytes());
+        out.extend_from_slice(&strings.0);
+        Ok(out)
+    }
+
+    /// Replaces the file with a rename, so that a reader maps either the old snapshot or the new one in full
+    #[allow(dead_code)]
+    pub fn publish(&self, path: &str) -> Result<(), Error> {
+        let data = self.encode(now())?;
+        let tmp = format!("{}.tmp", path);
+        let mut file = File::create(&tmp)?;
+        file.set_permissions(fs::Permissions::from_mode(0o644))?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// A read-only shared mapping of a whole snapshot file
+#[allow(dead_code)]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is never written through, and the daemon replaces the file rather than changing it in place
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+#[allow(dead_code)]
+impl Mapping {
+    fn new(file: &File, len: usize) -> Option<Self> {
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Self { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] { unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) } }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// The file a snapshot was mapped from, told apart by inode and mtime as every publish renames a new file in place
+#[allow(dead_code)]
+type FileId = (u64, u64, i64, i64);
+
+lazy_static! {
+    /// The last snapshot this process mapped, so that it is mapped once per publish rather than once per lookup
+    #[allow(dead_code)]
+    static ref LOADED: Mutex<Option<(FileId, Arc<Mapped>)>> = Mutex::new(None);
+}
+
+/// Binary searches `n` records, which `cmp` compares with the key, for the first equal one.
+/// `None` if a record could not be read.
+#[allow(dead_code)]
+fn search<F: Fn(usize) -> Option<Ordering>>(n: usize, cmp: F) -> Option<Option<usize>> {
+    let (mut lo, mut hi) = (0, n);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if cmp(mid)? == Ordering::Less {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo < n && cmp(lo)? == Ordering::Equal {
+        Some(Some(lo))
+    } else {
+        Some(None)
+    }
+}
+
+/// A mapped snapshot, read in place. Every read is bounds-checked, and a record that cannot be read
+/// leaves the lookup to the daemon.
+#[allow(dead_code)]
+pub struct Mapped {
+    map: Mapping,
+    published: u64,
+    npasswd: usize,
+    ngroups: usize,
+    nmembers: usize,
+    passwd_at: usize,
+    passwd_names_at: usize,
+    groups_at: usize,
+    group_names_at: usize,
+    members_at: usize,
+    user_groups_at: usize,
+    strings_at: usize,
+}
+
+#[allow(dead_code)]
+impl Mapped {
+    /// The snapshot, unless it is missing, stale, of another version or writable by anyone but root.
+    /// The file is mapped again only when the daemon has published a new one since the last call. The last mapping
+    /// is skipped while locked, as a child forked while another thread held the lock could never take it.
+    pub fn load(path: &str) -> Option<Arc<Self>> {
+        let file = File::open(path).ok()?;
+        let meta = file.metadata().ok()?;
+        if meta.uid() != 0 || meta.mode() & 0o022 != 0 {
+            return None;
+        }
+        let id = (meta.dev(), meta.ino(), meta.mtime(), meta.mtime_nsec());
+        let mut loaded = LOADED.try_lock().ok();
+        let mapped = match loaded.as_deref() {
+            Some(Some((mapped_id, mapped))) if *mapped_id == id => mapped.clone(),
+            _ => {
+                let mapped = Arc::new(Self::map(&file, usize::try_from(meta.len()).ok()?)?);
+                if let Some(loaded) = loaded.as_mut() {
+                    **loaded = Some((id, mapped.clone()));
+                }
+                mapped
+            }
+        };
+        if now().saturating_sub(mapped.published) > MAX_AGE {
+            log::debug!("snapshot is stale, published at {}", mapped.published);
+            return None;
+        }
+        Some(mapped)
+    }
+
+    /// Maps the file and checks that its header and sections add up to its length
+    pub fn map(file: &File, len: usize) -> Option<Self> {
+        if len < HEADER_LEN {
+            return None;
+        }
+        let map = Mapping::new(file, len)?;
+        let data = map.bytes();
+        if &data[..8] != MAGIC || be_u32(data, 8)? != SNAPSHOT_VERSION {
+            return None;
+        }
+        let npasswd = be_u32(data, 12)? as usize;
+        let published = be_u64(data, 16)?;
+        let ngroups = be_u32(data, 24)? as usize;
+        let nmembers = be_u32(data, 28)? as usize;
+        let strings_len = usize::try_from(be_u64(data, 32)?).ok()?;
+        let passwd_at = HEADER_LEN;
+        let passwd_names_at = passwd_at.checked_add(npasswd.checked_mul(PW_LEN)?)?;
+        let groups_at = passwd_names_at.checked_add(npasswd.checked_mul(INDEX_LEN)?)?;
+        let group_names_at = groups_at.checked_add(ngroups.checked_mul(GR_LEN)?)?;
+        let members_at = group_names_at.checked_add(ngroups.checked_mul(INDEX_LEN)?)?;
+        let user_groups_at = members_at.checked_add(nmembers.checked_mul(INDEX_LEN)?)?;
+        let strings_at = user_groups_at.checked_add(nmembers.checked_mul(INDEX_LEN)?)?;
+        if strings_at.checked_add(strings_len)? != len {
+            return None;
+        }
+        Some(Self { map,
+                    published,
+                    npasswd,
+                    ngroups,
+                    nmembers,
+                    passwd_at,
+                    passwd_names_at,
+                    groups_at,
+                    group_names_at,
+                    members_at,
+                    user_groups_at,
+                    strings_at })
+    }
+
+    fn data(&self) -> &[u8] { self.map.bytes() }
+
+    /// Where field `field` of record `i` lies in a section of `n` records of `len` bytes each
+    fn field(at: usize, n: usize, len: usize, i: usize, field: usize) -> Option<usize> {
+        if i >= n {
+            return None;
+        }
+        at.checked_add(i.checked_mul(len)?)?.checked_add(field)
+    }
+
+    fn index(&self, at: usize, n: usize, i: usize) -> Option<usize> {
+        be_u32(self.data(), Self::field(at, n, INDEX_LEN, i, 0)?).map(|i| i as usize)
+    }
+
+    fn string(&self, offset: u32) -> Option<&str> {
+        let at = self.strings_at.checked_add(offset as usize)?;
+        let len = be_u32(self.data(), at)? as usize;
+        let bytes = self.data().get(at + 4..(at + 4).checked_add(len)?)?;
+        std::str::from_utf8(bytes).ok()
+    }
+
+    fn pw_u64(&self, i: usize, field: usize) -> Option<u64> {
+        be_u64(self.data(), Self::field(self.passwd_at, self.npasswd, PW_LEN, i, field)?)
+    }
+
+    fn pw_u32(&self, i: usize, field: usize) -> Option<u32> {
+        be_u32(self.data(), Self::field(self.passwd_at, self.npasswd, PW_LEN, i, field)?)
+    }
+
+    fn pw_login(&self, i: usize) -> Option<&str> { self.string(self.pw_u32(i, 16)?) }
+
+    fn gr_gid(&self, g: usize) -> Option<u64> {
+        be_u64(self.data(), Self::field(self.groups_at, self.ngroups, GR_LEN, g, 0)?)
+    }
+
+    fn gr_u32(&self, g: usize, field: usize) -> Option<u32> {
+        be_u32(self.data(), Self::field(self.groups_at, self.ngroups, GR_LEN, g, field)?)
+    }
+
+    fn gr_name(&self, g: usize) -> Option<&str> { self.string(self.gr_u32(g, 8)?) }
+
+    /// The passwd record of the `j`th login in order
+    fn nth_login(&self, j: usize) -> Option<usize> { self.index(self.passwd_names_at, self.npasswd, j) }
+
+    /// The group record of the `j`th name in order
+    fn nth_name(&self, j: usize) -> Option<usize> { self.index(self.group_names_at, self.ngroups, j) }
+
+    fn pw_by_uid(&self, uid: u64) -> Option<Option<usize>> {
+        search(self.npasswd, |i| Some(self.pw_u64(i, 0)?.cmp(&uid)))
+    }
+
+    fn pw_by_login(&self, login: &str) -> Option<Option<usize>> {
+        let j = search(self.npasswd, |j| Some(self.pw_login(self.nth_login(j)?)?.cmp(login)))?;
+        match j {
+            Some(j) => Some(Some(self.nth_login(j)?)),
+            None => Some(None),
+        }
+    }
+
+    fn gr_by_gid(&self, gid: u64) -> Option<Option<usize>> { search(self.ngroups, |g| Some(self.gr_gid(g)?.cmp(&gid))) }
+
+    fn gr_by_name(&self, name: &str) -> Option<Option<usize>> {
+        let j = search(self.ngroups, |j| Some(self.gr_name(self.nth_name(j)?)?.cmp(name)))?;
+        match j {
+            Some(j) => Some(Some(self.nth_name(j)?)),
+            None => Some(None),
+        }
+    }
+
+    fn pw_message(&self, i: Option<usize>) -> Option<DaemonMessage> {
+        let i = match i {
+            Some(i) => i,
+            None => return Some(DaemonMessage::error(ErrorCode::NotFound, "not found")),
+        };
+        Some(DaemonMessage::Pw { login: String::from(self.pw_login(i)?),
+                                 uid: self.pw_u64(i, 0)?,
+                                 gid: self.pw_u64(i, 8)?,
+                                 home: String::from(self.string(self.pw_u32(i, 20)?)?),
+                                 sh: String::from(self.string(self.pw_u32(i, 24)?)?),
+                                 gecos: String::from(self.string(self.pw_u32(i, 28)?)?) })
+    }
+
+    fn gr_message(&self, g: Option<usize>) -> Option<DaemonMessage> {
+        let g = match g {
+            Some(g) => g,
+            None => return Some(DaemonMessage::error(ErrorCode::NotFound, "not found")),
+        };
+        let gid = self.gr_gid(g)?;
+        let name = String::from(self.gr_name(g)?);
+        let first = self.gr_u32(g, 12)? as usize;
+        let len = self.gr_u32(g, 16)? as usize;
+        let mut members = HashMap::new();
+        for m in first..first.checked_add(len)? {
+            let i = self.index(self.members_at, self.nmembers, m)?;
+            let login = String::from(self.pw_login(i)?);
+            members.insert(login.clone(),
+                           Member { id: self.pw_u64(i, 0)?,
+                                    login });
+        }
+        let sector = Sector { id: gid,
+                              name: name.clone(),
+                              sector_type: SectorType::Team };
+        Some(DaemonMessage::Gr { sector: SectorGroup { sector,
+                                                       gid: Some(gid),
+                                                       group: Some(name),
+                                                       members } })
+    }
+
+    /// Answers a passwd or group lookup the way the daemon would; other requests still go to the daemon
+    pub fn answer(&self, msg: &ClientMessage) -> Option<DaemonMessage> {
+        match msg {
+            ClientMessage::Pw(Pw::Uid(uid)) => self.pw_message(self.pw_by_uid(*uid)?),
+            ClientMessage::Pw(Pw::Nam(name)) => self.pw_message(self.pw_by_login(name)?),
+            ClientMessage::Gr(Gr::Gid(gid)) => self.gr_message(self.gr_by_gid(*gid)?),
+            ClientMessage::Gr(Gr::Nam(name)) => self.gr_message(self.gr_by_name(name)?),
+            _ => None,
+        }
+    }
+
+    /// The gids of the groups `login` belongs to, or `Some(None)` if it is nobody's login
+    pub fn groups_of(&self, login: &str) -> Option<Option<Vec<u64>>> {
+        let i = match self.pw_by_login(login)? {
+            Some(i) => i,
+            None => return Some(None),
+        };
+        let first = self.pw_u32(i, 32)? as usize;
+        let len = self.pw_u32(i, 36)? as usize;
+        let mut gids = Vec::with_capacity(len);
+        for n in first..first.checked_add(len)? {
+            gids.push(self.gr_gid(self.index(self.user_groups_at, self.nmembers, n)?)?);
+        }
+        Some(Some(gids))
+    }
+}
+
+#[allow(dead_code)]
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(<[u8; 4]>::try_from(data.get(at..at.checked_add(4)?)?).ok()?))
+}
+
+#[allow(dead_code)]
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(<[u8; 8]>::try_from(data.get(at..at.checked_add(8)?)?).ok()?))
+}
@@ -3,8 +3,12 @@ use std::path::Path;
 use std::string::String;
 
 const DEFAULT_CONF_PATH_STR: &str = "/etc/sectora.conf";
+const DEFAULT_CLIENT_CONF_PATH_STR: &str = "/etc/sectora.client.conf";
 
 lazy_static! {
     static ref CONF_PATH_STR: String = env::var("SECTORA_CONFIG").unwrap_or(String::from(DEFAULT_CONF_PATH_STR));
     pub static ref CONF_PATH: &'static Path = Path::new(&(*CONF_PATH_STR));
+    static ref CLIENT_CONF_PATH_STR: String =
+        env::var("SECTORA_CLIENT_CONFIG").unwrap_or(String::from(DEFAULT_CLIENT_CONF_PATH_STR));
+    pub static ref CLIENT_CONF_PATH: &'static Path = Path::new(&(*CLIENT_CONF_PATH_STR));
 }
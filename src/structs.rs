@@ -1,5 +1,5 @@
-use crate::error::{Error, ParseSectorError, ParseSectorGroupError, ParseSectorTypeError};
-use std::collections::HashMap;
+use crate::error::{Error, ParseMemberError, ParseSectorError, ParseSectorGroupError, ParseSectorTypeError};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
@@ -14,6 +14,12 @@ pub struct Config {
     pub team: Vec<TeamConfig>,
     #[serde(default = "default_repo")]
     pub repo: Vec<RepoConfig>,
+    #[serde(default)]
+    pub member: Vec<MemberConfig>,
+    #[serde(default)]
+    pub netgroup: Vec<NetgroupConfig>,
+    #[serde(default)]
+    pub aging: AgingConfig,
     #[serde(default = "default_endpoint")]
     pub endpoint: String,
     #[serde(default = "default_home")]
@@ -22,13 +28,29 @@ pub struct Config {
     pub sh: String,
     #[serde(default = "default_cache_duration")]
     pub cache_duration: u64,
+    #[serde(default = "default_cache_max_age")]
+    pub cache_max_age: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    #[serde(default)]
+    pub offline: OfflinePolicy,
+    #[serde(default)]
+    pub gecos: GecosPolicy,
+    #[serde(default = "default_negative_cache_ttl")]
+    pub negative_cache_ttl: u64,
+    #[serde(default = "default_negative_cache_size")]
+    pub negative_cache_size: usize,
     #[serde(default = "default_cert_path")]
     pub cert_path: String,
     #[serde(default = "default_user_conf_path")]
     pub user_conf_path: String,
     #[serde(default = "default_cache_dir")]
     pub cache_dir: String,
+    pub admin_gid: Option<u32>,
+    #[serde(default = "default_legacy_protocol")]
+    pub legacy_protocol: bool,
     pub proxy_url: Option<String>,
+    pub webhook: Option<WebhookConfig>,
 }
 
 fn default_team() -> Vec<TeamConfig> { Vec::new() }
@@ -37,6 +59,10 @@ fn default_endpoint() -> String { String::from("https://api.github.com") }
 fn default_home() -> String { String::from("/home/{}") }
 fn default_sh() -> String { String::from("/bin/bash") }
 fn default_cache_duration() -> u64 { 3600 }
+fn default_cache_max_age() -> u64 { 86400 }
+fn default_request_timeout() -> u64 { 10 }
+fn default_negative_cache_ttl() -> u64 { 60 }
+fn default_negative_cache_size() -> usize { 4096 }
 fn default_cert_path() -> String { String::from("/etc/ssl/certs/ca-certificates.crt") }
 fn default_user_conf_path() -> String { String::from(".config/sectora.toml") }
 fn default_cache_dir() -> String {
@@ -45,17 +71,9 @@ fn default_cache_dir() -> String {
     String::from(path.as_os_str().to_str().unwrap_or_default())
 }
 
-fn get_socket_path() -> String {
-    let mut path = std::env::temp_dir();
-    path.push("sectorad");
-    String::from(path.as_os_str().to_str().unwrap_or_default())
-}
-
-fn get_socket_dir() -> String {
-    let mut path = std::env::temp_dir();
-    path.push("sectora");
-    String::from(path.as_os_str().to_str().unwrap_or_default())
-}
+fn default_socket_dir() -> String { String::from("/run/sectora") }
+fn default_socket_timeout() -> u64 { 5 }
+fn default_legacy_protocol() -> bool { true }
 
 impl Config {
     #[allow(dead_code)]
@@ -65,18 +83,277 @@ impl Config {
         file.read_to_string(&mut contents)?;
         Ok(toml::from_str::<Config>(&contents)?)
     }
+
+    /// Rejects settings that parse but cannot work, so that a broken reload keeps the running config
+    #[allow(dead_code)]
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::InvalidConfig(msg));
+        if self.token.is_empty() || self.org.is_empty() {
+            return invalid(String::from("token and org are required"));
+        }
+        let mut teams = HashSet::new();
+        if let Some(team) = self.team.iter().find(|t| !teams.insert(&t.name)) {
+            return invalid(format!("duplicated team: {}", team.name));
+        }
+        let mut repos = HashSet::new();
+        if let Some(repo) = self.repo.iter().find(|r| !repos.insert(&r.name)) {
+            return invalid(format!("duplicated repo: {}", repo.name));
+        }
+        let mut gids = HashSet::new();
+        for gid in self.team
+                       .iter()
+                       .filter_map(|t| t.gid)
+                       .chain(self.repo.iter().filter_map(|r| r.gid))
+        {
+            if !gids.insert(gid) {
+                return invalid(format!("duplicated gid: {}", gid));
+            }
+        }
+        let mut netgroups = HashSet::new();
+        if let Some(netgroup) = self.netgroup.iter().find(|n| !netgroups.insert(&n.name)) {
+            return invalid(format!("duplicated netgroup: {}", netgroup.name));
+        }
+        // NOTE: a sector is a netgroup named like its group, which would shadow a configured one
+        let groups: HashSet<&String> = self.team
+                                           .iter()
+                                           .map(|t| t.group.as_ref().unwrap_or(&t.name))
+                                           .chain(self.repo.iter().map(|r| r.group.as_ref().unwrap_or(&r.name)))
+                                           .collect();
+        if let Some(netgroup) = self.netgroup.iter().find(|n| groups.contains(&n.name)) {
+            return invalid(format!("netgroup named like a group: {}", netgroup.name));
+        }
+        for expire in self.team
+                          .iter()
+                          .filter_map(|t| t.expire.as_ref())
+                          .chain(self.repo.iter().filter_map(|r| r.expire.as_ref()))
+                          .chain(self.member.iter().filter_map(|m| m.expire.as_ref()))
+        {
+            if days_from_date(expire).is_none() {
+                return invalid(format!("invalid expiry date: {}", expire));
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            if webhook.listen.parse::<std::net::SocketAddr>().is_err() {
+                return invalid(format!("invalid webhook listen address: {}", webhook.listen));
+            }
+            if webhook.secret.is_empty() {
+                return invalid(String::from("webhook secret is empty"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Offline policy for what belongs to `login` itself, such as its keys: fail-open if any sector it is in is
+    #[allow(dead_code)]
+    pub fn offline_of(&self, login: &str, sectors: &[SectorGroup]) -> OfflinePolicy {
+        for sector in sectors.iter().filter(|s| s.members.contains_key(login)) {
+            let name = &sector.sector.name;
+            let policy = match sector.sector.sector_type {
+                SectorType::Team => self.team.iter().find(|t| &t.name == name).and_then(|t| t.offline),
+                SectorType::Repo => self.repo.iter().find(|r| &r.name == name).and_then(|r| r.offline),
+            };
+            if policy.unwrap_or(self.offline) == OfflinePolicy::FailOpen {
+                return OfflinePolicy::FailOpen;
+            }
+        }
+        self.offline
+    }
+
+    /// Day (since the epoch) the account of `login` expires: its own date if set, otherwise the latest date of
+    /// the sectors it is in, unless one of them does not expire at all
+    #[allow(dead_code)]
+    pub fn expire_of(&self, login: &str, sectors: &[SectorGroup]) -> Option<i64> {
+        if let Some(expire) = self.member.iter().find(|m| m.login == login).and_then(|m| m.expire.as_ref()) {
+            return days_from_date(expire);
+        }
+        let mut latest = None;
+        for sector in sectors.iter().filter(|s| s.members.contains_key(login)) {
+            let name = &sector.sector.name;
+            let expire = match sector.sector.sector_type {
+                SectorType::Team => self.team.iter().find(|t| &t.name == name).and_then(|t| t.expire.as_ref()),
+                SectorType::Repo => self.repo.iter().find(|r| &r.name == name).and_then(|r| r.expire.as_ref()),
+            };
+            match expire.and_then(|e| days_from_date(e)) {
+                Some(day) => latest = std::cmp::max(latest, Some(day)),
+                None => return None,
+            }
+        }
+        latest
+    }
+}
+
+/// Days since 1970-01-01 of a `YYYY-MM-DD` date, as shadow(5) counts them; dates that do not exist are refused
+#[allow(dead_code)]
+pub fn days_from_date(date: &str) -> Option<i64> {
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.iter().map(|p| p.len()).collect::<Vec<usize>>() != [4, 2, 2]
+       || !parts.iter().all(|p| p.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return None;
+    }
+    let (y, m, d) = (parts[0].parse::<i64>().ok()?, parts[1].parse::<i64>().ok()?, parts[2].parse::<i64>().ok()?);
+    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+    let days_in_month = match m {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&d) {
+        return None;
+    }
+    // NOTE: days_from_civil of http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+/// Password aging applied to every member with a password, in days; unset fields are left out of shadow entries
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AgingConfig {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub warn: Option<i64>,
+    pub inact: Option<i64>,
+}
+
+/// A netgroup of hosts, for the host part of `/etc/exports` and sudoers rules
+#[derive(Deserialize, Debug, Clone)]
+pub struct NetgroupConfig {
+    pub name: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MemberConfig {
+    pub login: String,
+    pub expire: Option<String>,
+}
+
+/// The aging fields of a shadow entry; -1 leaves a field empty
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Aging {
+    pub lstchg: i64,
+    pub min: i64,
+    pub max: i64,
+    pub warn: i64,
+    pub inact: i64,
+    pub expire: i64,
+}
+
+impl Default for Aging {
+    fn default() -> Self {
+        Aging { lstchg: -1,
+                min: -1,
+                max: -1,
+                warn: -1,
+                inact: -1,
+                expire: -1 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    #[serde(default = "default_webhook_listen")]
+    pub listen: String,
+    pub secret: String,
+}
+
+fn default_webhook_listen() -> String { String::from("127.0.0.1:8080") }
+
+/// What to do with a cached member list when GitHub is unreachable and the cache is older than `cache_max_age`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OfflinePolicy {
+    FailOpen,
+    FailClosed,
+}
+
+impl Default for OfflinePolicy {
+    fn default() -> Self { OfflinePolicy::FailClosed }
+}
+
+/// What of a member's GitHub profile goes into the gecos field
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GecosPolicy {
+    Off,
+    Name,
+    NameEmail,
+}
+
+impl Default for GecosPolicy {
+    fn default() -> Self { GecosPolicy::Name }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+impl Profile {
+    /// The full name, and with `NameEmail` the email in the last subfield; separators of passwd and gecos are dropped
+    #[allow(dead_code)]
+    pub fn gecos(&self, policy: GecosPolicy) -> String {
+        let clean = |s: &Option<String>| -> String {
+            s.as_ref()
+             .map(|s| s.chars().filter(|c| *c != ':' && *c != ',' && !c.is_control()).collect())
+             .unwrap_or_default()
+        };
+        match policy {
+            GecosPolicy::Off => String::new(),
+            GecosPolicy::Name => clean(&self.name),
+            GecosPolicy::NameEmail => match clean(&self.email) {
+                email if email.is_empty() => clean(&self.name),
+                email => format!("{},,,,{}", clean(&self.name), email),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SocketConfig {
     pub socket_path: String,
-    pub socket_dir: String,
+    pub snapshot_path: String,
+    pub timeout: u64,
+}
+
+#[derive(Deserialize)]
+struct SocketSettings {
+    #[serde(default = "default_socket_dir")]
+    socket_dir: String,
+    #[serde(default = "default_socket_timeout")]
+    socket_timeout: u64,
 }
 
 impl SocketConfig {
-    pub fn new() -> Self {
-        SocketConfig { socket_path: get_socket_path(),
-                       socket_dir: get_socket_dir() }
+    pub fn new(socket_dir: &str, timeout: u64) -> Self {
+        SocketConfig { socket_path: format!("{}/sectorad", socket_dir),
+                       snapshot_path: format!("{}/snapshot", socket_dir),
+                       timeout }
+    }
+
+    /// Reads the world-readable client config; a missing file means the defaults
+    #[allow(dead_code)]
+    pub fn load(configpath: &std::path::Path) -> Result<Self, Error> {
+        let s = match std::fs::read_to_string(configpath) {
+            Ok(contents) => toml::from_str::<SocketSettings>(&contents)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => toml::from_str::<SocketSettings>("")?,
+            Err(e) => return Err(Error::from(e)),
+        };
+        Ok(Self::new(&s.socket_dir, s.socket_timeout))
+    }
+
+    /// Same as `load`, but falls back to the defaults on any error
+    #[allow(dead_code)]
+    pub fn from_path(configpath: &std::path::Path) -> Self {
+        Self::load(configpath).unwrap_or_else(|_| Self::new(&default_socket_dir(), default_socket_timeout()))
     }
 }
 
@@ -107,6 +384,8 @@ pub struct TeamConfig {
     pub name: String,
     pub gid: Option<u64>,
     pub group: Option<String>,
+    pub offline: Option<OfflinePolicy>,
+    pub expire: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -120,6 +399,8 @@ pub struct RepoConfig {
     pub name: String,
     pub gid: Option<u64>,
     pub group: Option<String>,
+    pub offline: Option<OfflinePolicy>,
+    pub expire: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -189,7 +470,7 @@ impl FromStr for Sector {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SectorGroup {
     pub sector: Sector,
     pub gid: Option<u64>,
@@ -224,6 +505,9 @@ impl FromStr for SectorGroup {
     type Err = ParseSectorGroupError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts = s.split('\t').collect::<Vec<&str>>();
+        if parts.len() != 4 {
+            return Err(ParseSectorGroupError::BadFormat);
+        }
         let sector = parts[0].parse().map_err(ParseSectorGroupError::Sector)?;
         let gid: Option<u64> = match parts[1] {
             "" => None,
@@ -234,6 +518,7 @@ impl FromStr for SectorGroup {
             s => Some(String::from(s)),
         };
         let members = parts[3].split(' ')
+                              .filter(|s| !s.is_empty())
                               .map(|s| s.parse::<Member>().map_err(ParseSectorGroupError::Member))
                               .collect::<Result<Vec<Member>, _>>()?
                               .into_iter()
@@ -257,10 +542,13 @@ impl fmt::Display for Member {
 }
 
 impl FromStr for Member {
-    type Err = std::num::ParseIntError;
+    type Err = ParseMemberError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts = s.split(':').collect::<Vec<&str>>();
-        Ok(Self { id: parts[0].parse()?,
+        if parts.len() != 2 {
+            return Err(ParseMemberError::BadFormat);
+        }
+        Ok(Self { id: parts[0].parse().map_err(ParseMemberError::Id)?,
                   login: String::from(parts[1]) })
     }
 }
@@ -276,11 +564,14 @@ impl fmt::Display for MemberGid {
 }
 
 impl FromStr for MemberGid {
-    type Err = std::num::ParseIntError;
+    type Err = ParseMemberError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts = s.split('|').collect::<Vec<&str>>();
+        if parts.len() != 2 {
+            return Err(ParseMemberError::BadFormat);
+        }
         Ok(Self { member: parts[0].parse()?,
-                  gid: parts[1].parse()? })
+                  gid: parts[1].parse().map_err(ParseMemberError::Gid)? })
     }
 }
 
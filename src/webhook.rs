@@ -0,0 +1,153 @@
+use crate::error::Error;
+use crate::ghclient::{self, SharedClient};
+use crate::structs::WebhookConfig;
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// GitHub caps webhook payloads at 25 MB
+const MAX_PAYLOAD_LEN: usize = 25 * 1024 * 1024;
+
+/// Receives GitHub webhooks, drops the caches of the sectors they affect and asks the daemon over `refresh` to refetch
+pub async fn serve(client: SharedClient, conf: WebhookConfig, refresh: mpsc::Sender<()>) -> Result<(), Error> {
+    let addr: SocketAddr =
+        conf.listen
+            .parse()
+            .map_err(|_| {
+                Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid webhook listen address"))
+            })?;
+    let secret = Arc::new(conf.secret);
+    let make_svc = make_service_fn(move |_| {
+        let client = client.clone();
+        let secret = secret.clone();
+        let refresh = refresh.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(client.clone(), secret.clone(), refresh.clone(), req)))
+        }
+    });
+    log::info!("Listening webhooks @ {}", addr);
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+    Ok(())
+}
+
+async fn handle(client: SharedClient, secret: Arc<String>, mut refresh: mpsc::Sender<()>, req: Request<Body>)
+                -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(respond(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let event = header_str(&req, "X-GitHub-Event");
+    let signature = header_str(&req, "X-Hub-Signature-256");
+    let declared = header_str(&req, header::CONTENT_LENGTH.as_str()).parse::<usize>().unwrap_or(0);
+    if declared > MAX_PAYLOAD_LEN {
+        return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    let body = match read_body(req.into_body()).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE)),
+        Err(_) => return Ok(respond(StatusCode::BAD_REQUEST)),
+    };
+    if !verify(&secret, &body, &signature) {
+        log::warn!("webhook signature mismatch (event: {})", event);
+        return Ok(respond(StatusCode::UNAUTHORIZED));
+    }
+    let payload: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(_) => return Ok(respond(StatusCode::BAD_REQUEST)),
+    };
+    let client = ghclient::current(&client);
+    let org = payload["organization"]["login"].as_str().unwrap_or_default();
+    if event != "ping" && org != client.conf.org {
+        return Ok(respond(StatusCode::NO_CONTENT));
+    }
+    log::info!("webhook: {} {}", event, payload["action"].as_str().unwrap_or_default());
+    let result = match event.as_str() {
+        "ping" => return Ok(respond(StatusCode::OK)),
+        "membership" => match payload["team"]["id"].as_u64() {
+            Some(id) => client.invalidate(&client.team_members_url(id))
+                              .and(client.invalidate(&client.team_maintainers_url(id))),
+            None => Ok(()),
+        },
+        "team" => {
+            let members = match payload["team"]["id"].as_u64() {
+                Some(id) => client.invalidate(&client.team_members_url(id))
+                                  .and(client.invalidate(&client.team_maintainers_url(id))),
+                None => Ok(()),
+            };
+            members.and(client.invalidate(&client.team_map_url()))
+        }
+        "member" => match payload["repository"]["name"].as_str() {
+            Some(name) => client.invalidate(&client.repo_collaborators_url(name)),
+            None => Ok(()),
+        },
+        "organization" => client.invalidate_sectors(),
+        _ => return Ok(respond(StatusCode::NO_CONTENT)),
+    };
+    if let Err(e) = result {
+        log::warn!("failed to invalidate caches: {:?}", e);
+        return Ok(respond(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+    // NOTE: a full channel means a refetch is already pending, which will see these caches marked stale too
+    let _ = refresh.try_send(());
+    Ok(respond(StatusCode::ACCEPTED))
+}
+
+/// Reads the body unless it grows past `MAX_PAYLOAD_LEN`, as it is read before the signature can be checked
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > MAX_PAYLOAD_LEN {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+fn respond(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
+
+fn header_str(req: &Request<Body>, name: &str) -> String {
+    String::from(req.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default())
+}
+
+fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let expected = match signature.get(..7) {
+        Some("sha256=") => match decode_hex(signature.get(7..).unwrap_or_default()) {
+            Some(expected) => expected,
+            None => return false,
+        },
+        _ => return false,
+    };
+    let digest = PKey::hmac(secret.as_bytes()).and_then(|key| {
+                                                  let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+                                                  signer.update(body)?;
+                                                  signer.sign_to_vec()
+                                              });
+    match digest {
+        Ok(digest) => digest.len() == expected.len() && openssl::memcmp::eq(&digest, &expected),
+        Err(_) => false,
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+                .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                .collect()
+}
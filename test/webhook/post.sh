@@ -0,0 +1,10 @@
+#!/bin/sh -e
+# usage: post.sh EVENT PAYLOAD [SECRET]
+secret=${3:-TESTSECRET}
+sig=$(openssl dgst -sha256 -hmac "$secret" < "$2" | sed 's/^.* //')
+curl -s -o /dev/null -w '%{http_code}' \
+    -H "X-GitHub-Event: $1" \
+    -H "X-Hub-Signature-256: sha256=$sig" \
+    -H "Content-Type: application/json" \
+    --data-binary @"$2" \
+    http://host:8080/